@@ -0,0 +1,88 @@
+// Code generation time on large generated programs, which should grow in
+// step with their size. Run with `cargo bench`.
+
+use bfinterp::bf;
+
+use std::time::{Duration, Instant};
+
+// Pieces of the kind compilers to Brainfuck emit, many of them loops the
+// optimizer replaces
+const PIECES: &[&str] = &[
+    "[-]", "[->+<]", "[->>+++<<]", "[-<+>>+<]", "+++++", "---", ">>", "<<<", ">", "<", ".", "[>]", "[<<]",
+];
+
+// Minimal-standard LCG, so every run times the same program
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 = self.0 * 48271 % 0x7fff_ffff;
+        self.0 as usize % n
+    }
+}
+
+// About `size` bytes of commands: pieces at the top level, and now and then
+// loops nested up to `depth` deep with pieces around each level
+fn program(size: usize, depth: usize) -> Vec<u8> {
+    let mut rng = Rng(1);
+    let mut text = Vec::with_capacity(size);
+    while text.len() < size {
+        match rng.below(32) {
+            0 => {
+                let depth = 1 + rng.below(depth);
+                nest(&mut text, &mut rng, depth)
+            }
+            _ => piece(&mut text, &mut rng),
+        }
+    }
+    text
+}
+
+fn nest(text: &mut Vec<u8>, rng: &mut Rng, depth: usize) {
+    piece(text, rng);
+    if depth > 0 {
+        text.push(b'[');
+        nest(text, rng, depth - 1);
+        text.push(b']');
+    }
+    piece(text, rng);
+}
+
+fn piece(text: &mut Vec<u8>, rng: &mut Rng) {
+    text.extend_from_slice(PIECES[rng.below(PIECES.len())].as_bytes())
+}
+
+fn time<T, F: FnOnce() -> T>(f: F) -> (T, Duration) {
+    let start = Instant::now();
+    let result = f();
+    (result, start.elapsed())
+}
+
+fn main() {
+    let mandelbrot = include_bytes!("../bf/mandelbrot.bf");
+    let mut cases: Vec<(String, Vec<u8>)> = Vec::new();
+    for &mb in &[1, 2, 4, 8] {
+        cases.push((format!("generated {} MB", mb), program(mb << 20, 64)));
+    }
+    cases.push(("mandelbrot x 400".to_string(), mandelbrot.repeat(400)));
+
+    println!("{:<20} {:>10} {:>10} {:>12} {:>10}", "program", "bytes", "parse", "codegen", "MB/s");
+    for (name, source) in &cases {
+        let (nodes, parse) = time(|| {
+            bf::Parser::new(bf::Lexer::new(&source[..]))
+                .collect::<bf::Result<Vec<_>>>()
+                .unwrap()
+        });
+        let (program, codegen) = time(|| bf::generate_code(nodes).unwrap());
+        let rate = source.len() as f64 / (1 << 20) as f64 / codegen.as_secs_f64();
+        println!(
+            "{:<20} {:>10} {:>10.1?} {:>12.1?} {:>10.1}   {} instructions",
+            name,
+            source.len(),
+            parse,
+            codegen,
+            rate,
+            program.instructions.len()
+        );
+    }
+}
@@ -1,16 +1,61 @@
-pub(self) mod common;
+mod common;
+pub use common::{Instruction, Lexeme, Node, NodeKind, Position, Program, Span, Token};
+
+mod cell;
+pub use cell::{Cell, CellWidth};
+
+mod config;
+pub use config::{Config, EofPolicy, TapePolicy};
+
+mod limits;
+pub use limits::{Limit, Limits, Usage};
+
+mod error;
+pub use error::{Error, Result};
+
+mod tape;
 
 mod lexer;
-pub use lexer::Lexer;
+pub use lexer::{Lexer, Lossless};
 
 mod parser;
 pub use parser::Parser;
 
+mod opt;
+pub use opt::{OptConfig, Pass};
+
 mod codegen;
-pub use codegen::generate_code;
+pub use codegen::{generate_code, generate_code_with, generate_raw_code};
 
 mod vm;
-pub use vm::Interpreter;
+pub use vm::{Interpreter, Status};
+
+mod profile;
+pub use profile::{LoopProfile, Observer, Profile};
+
+mod binary;
+
+mod bytecode;
+pub use bytecode::Bytecode;
+
+mod snapshot;
+pub use snapshot::Snapshot;
+
+mod disasm;
+pub use disasm::{disassemble, Mnemonic};
+
+mod format;
+pub use format::{format, Layout};
+
+mod debugger;
+pub use debugger::{Debugger, Stop};
+
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+mod jit;
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+pub use jit::Jit;
+
+pub mod backend;
 
 mod oldvm;
 pub use oldvm::BFInterpreter as OldInterpreter;
@@ -0,0 +1,28 @@
+// Translations of an optimized program into source code for other compilers,
+// or back into Brainfuck
+
+use super::cell::CellWidth;
+
+pub mod brainfuck;
+pub mod c;
+pub mod rust;
+pub mod wasm;
+
+// Operator and operand for adding `amount` to a cell, picking whichever of +
+// and - gives the smaller number. None when the add is a no-op at this width.
+fn add_operand(amount: i64, width: CellWidth) -> Option<(char, u64)> {
+    let mask = u64::MAX >> (64 - width.bits());
+    let v = wrapped(amount, width);
+    if v == 0 {
+        None
+    } else if v > mask / 2 {
+        Some(('-', mask - v + 1))
+    } else {
+        Some(('+', v))
+    }
+}
+
+// `amount` as the unsigned value a cell of `width` bits holds for it
+fn wrapped(amount: i64, width: CellWidth) -> u64 {
+    amount as u64 & (u64::MAX >> (64 - width.bits()))
+}
@@ -0,0 +1,235 @@
+// Brainfuck backend: turns code back into plain commands, dropping comments
+// and layout, for minifying programs and checking the optimizer
+
+use super::super::cell::CellWidth;
+use super::super::common::{Instruction, Node, NodeKind, Program};
+use super::add_operand;
+use Instruction::*;
+
+use std::io::{self, Write};
+
+/// Writes the commands `program` was compiled from, in their shortest form for
+/// cells of `width` bits, on a single line. Fused instructions expand back to
+/// the loops they replaced. A run of `MulAdd` must end in a zeroing
+/// instruction, as codegen leaves it, since the loop it came from is what
+/// zeroes the cell. Amounts are taken modulo the cell width, and an offset or
+/// amount that would still take more than `LONGEST_RUN` commands is an error.
+pub fn emit<W: Write>(program: &Program, width: CellWidth, out: &mut W) -> io::Result<()> {
+    let mut text = Vec::new();
+    let mut targets = Vec::new();
+    // Where the written commands leave the pointer, relative to where the
+    // instructions have it. Instructions with an offset move it only as far
+    // as they need to, and the rest move it back first.
+    let mut shift = 0i64;
+    // Which way the instructions just moved the pointer, zero after anything
+    // else. The written commands get to where that left it before heading
+    // back the other way, so that the same cells are checked.
+    let mut leg = 0i64;
+    for &instr in &program.instructions {
+        fits(instr, width)?;
+        let to = match instr {
+            Add(o, _) | Set(o, _) | Write(o) => o as i64,
+            Right(n) => n as i64,
+            Left(n) => -(n as i64),
+            _ => 0,
+        };
+        if (leg > 0 && shift < 0 && to < 0) || (leg < 0 && shift > 0 && to > 0) {
+            flush_moves(&mut text, &mut -shift);
+            shift = 0;
+        }
+        match instr {
+            Add(o, _) | Set(o, _) | Write(o) => {
+                flush_moves(&mut text, &mut (o as i64 - shift));
+                shift = o as i64;
+            }
+            Right(_) | Left(_) => {
+                shift -= to;
+                if shift.unsigned_abs() > LONGEST_RUN {
+                    return Err(too_long(instr));
+                }
+                leg = to.signum();
+                continue;
+            }
+            _ => {
+                flush_moves(&mut text, &mut -shift);
+                shift = 0;
+            }
+        }
+        leg = 0;
+        if let MulAdd(offset, factor) = instr {
+            targets.push((offset as i64, factor));
+            continue;
+        }
+        let instr = if targets.is_empty() {
+            instr
+        } else {
+            mul_loop(&mut text, &targets, width);
+            targets.clear();
+            // The loop did the zeroing that comes next
+            match instr {
+                Zero => continue,
+                Set(0, v) => Add(0, v),
+                ZeroRight(n) if n > 0 => {
+                    text.push(b'>');
+                    ZeroRight(n - 1)
+                }
+                ZeroLeft(n) if n > 0 => {
+                    text.push(b'<');
+                    ZeroLeft(n - 1)
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "mul_add without a zero after it has no Brainfuck form",
+                    ))
+                }
+            }
+        };
+        match instr {
+            Add(_, n) => {
+                if let Some((op, n)) = add_operand(n, width) {
+                    repeat(&mut text, op as u8, n)
+                }
+            }
+            Set(_, v) => {
+                text.extend_from_slice(b"[-]");
+                if let Some((op, v)) = add_operand(v, width) {
+                    repeat(&mut text, op as u8, v)
+                }
+            }
+            Jez(_) => text.push(b'['),
+            Jnz(_) => text.push(b']'),
+            SearchLeft(n) => wrap(&mut text, |t| repeat(t, b'<', n as u64)),
+            SearchRight(n) => wrap(&mut text, |t| repeat(t, b'>', n as u64)),
+            AddMoveRight(n) => add_move(&mut text, b'>', b'<', n),
+            AddMoveLeft(n) => add_move(&mut text, b'<', b'>', n),
+            ZeroRight(n) => (0..n).for_each(|_| text.extend_from_slice(b"[-]>")),
+            ZeroLeft(n) => (0..n).for_each(|_| text.extend_from_slice(b"[-]<")),
+            Mandel(x, n) => wrap(&mut text, |t| {
+                repeat(t, b'>', x as u64);
+                add_move(t, b'>', b'<', n);
+                repeat(t, b'<', x as u64 + n as u64);
+            }),
+            Zero => text.extend_from_slice(b"[-]"),
+            Write(_) => text.push(b'.'),
+            Read => text.push(b','),
+            Halt => {}
+            // Gathered into `targets` above, and moves into `shift`
+            MulAdd(..) | Left(_) | Right(_) => {}
+        }
+    }
+    text.push(b'\n');
+    out.write_all(&text)
+}
+
+/// Most commands written in a row for one operand. Bytecode read from a file
+/// can hold any amount, which at 64 bits would be far too much text.
+pub const LONGEST_RUN: u64 = 1 << 24;
+
+// Checks that the operands of `instr` can be written out
+fn fits(instr: Instruction, width: CellWidth) -> io::Result<()> {
+    let (offset, amount) = match instr {
+        Add(o, n) | Set(o, n) | MulAdd(o, n) => (o, add_operand(n, width).map_or(0, |(_, n)| n)),
+        Write(o) => (o, 0),
+        _ => return Ok(()),
+    };
+    if offset.unsigned_abs() as u64 > LONGEST_RUN || amount > LONGEST_RUN {
+        return Err(too_long(instr));
+    }
+    Ok(())
+}
+
+fn too_long(instr: Instruction) -> io::Error {
+    let msg = format!("{:?} needs too many commands to write as Brainfuck", instr);
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Writes the commands in `nodes`, on a single line, with each run of `+` and
+/// `-` cut down to its net effect. Moves are kept as they are, as a run of
+/// them that turns back checks the cells it goes out to.
+pub fn emit_nodes<W: Write>(nodes: &[Node], out: &mut W) -> io::Result<()> {
+    let mut text = Vec::new();
+    write_nodes(nodes, &mut text);
+    text.push(b'\n');
+    out.write_all(&text)
+}
+
+fn write_nodes(nodes: &[Node], text: &mut Vec<u8>) {
+    let (mut add, mut moves) = (0i64, 0i64);
+    for node in nodes {
+        match &node.kind {
+            NodeKind::Increment | NodeKind::Decrement => {
+                flush_moves(text, &mut moves);
+                add += if node.kind == NodeKind::Increment { 1 } else { -1 };
+                continue;
+            }
+            NodeKind::MoveRight | NodeKind::MoveLeft => {
+                flush_add(text, &mut add);
+                let step = if node.kind == NodeKind::MoveRight { 1 } else { -1 };
+                if moves.signum() == -step {
+                    flush_moves(text, &mut moves);
+                }
+                moves += step;
+                continue;
+            }
+            _ => {}
+        }
+        flush_add(text, &mut add);
+        flush_moves(text, &mut moves);
+        match &node.kind {
+            NodeKind::Loop(body) => wrap(text, |t| write_nodes(body, t)),
+            NodeKind::Output => text.push(b'.'),
+            NodeKind::Input => text.push(b','),
+            _ => {}
+        }
+    }
+    flush_add(text, &mut add);
+    flush_moves(text, &mut moves);
+}
+
+fn flush_add(text: &mut Vec<u8>, add: &mut i64) {
+    let op = if *add < 0 { b'-' } else { b'+' };
+    repeat(text, op, add.unsigned_abs());
+    *add = 0;
+}
+
+fn flush_moves(text: &mut Vec<u8>, moves: &mut i64) {
+    let op = if *moves < 0 { b'<' } else { b'>' };
+    repeat(text, op, moves.unsigned_abs());
+    *moves = 0;
+}
+
+fn repeat(text: &mut Vec<u8>, op: u8, n: u64) {
+    text.extend((0..n).map(|_| op))
+}
+
+fn wrap<F: FnOnce(&mut Vec<u8>)>(text: &mut Vec<u8>, body: F) {
+    text.push(b'[');
+    body(text);
+    text.push(b']');
+}
+
+// `[->++>+++<<]`, adding each factor at its offset
+fn mul_loop(text: &mut Vec<u8>, targets: &[(i64, i64)], width: CellWidth) {
+    wrap(text, |t| {
+        t.push(b'-');
+        let mut at = 0;
+        for &(offset, factor) in targets {
+            flush_moves(t, &mut (offset - at));
+            at = offset;
+            if let Some((op, n)) = add_operand(factor, width) {
+                repeat(t, op as u8, n)
+            }
+        }
+        flush_moves(t, &mut -at);
+    })
+}
+
+// `[->+<]` with `there` and `back` as the moves
+fn add_move(text: &mut Vec<u8>, there: u8, back: u8, n: u16) {
+    text.extend_from_slice(b"[-");
+    repeat(text, there, n as u64);
+    text.push(b'+');
+    repeat(text, back, n as u64);
+    text.push(b']');
+}
@@ -0,0 +1,276 @@
+// Emits a standalone C program equivalent to an optimized Instruction list
+
+use super::{add_operand, wrapped};
+use super::super::cell::CellWidth;
+use super::super::common::{Instruction, Program};
+use super::super::config::{Config, EofPolicy, TapePolicy};
+use Instruction::*;
+
+use std::cmp;
+use std::io::{self, Write};
+
+const HEADER: &str = "\
+#include <stddef.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+";
+
+// Everything after the cell type and tape size, up to the policy-specific parts
+const RUNTIME: &str = r#"
+static cell *tape;
+static size_t len = TAPE_SIZE;
+static size_t p;
+
+static void fail(const char *at, const char *msg) {
+    fflush(stdout);
+    if (at)
+        fprintf(stderr, "%s: %s\n", at, msg);
+    else
+        fprintf(stderr, "%s\n", msg);
+    exit(3);
+}
+"#;
+
+// Tape growth, shared by both growing policies
+const GROW_RIGHT: &str = r#"
+static void grow_right(size_t target) {
+    size_t n = target + 1 > len * 2 ? target + 1 : len * 2;
+    cell *t = realloc(tape, n * sizeof *tape);
+    if (!t)
+        fail(NULL, "out of memory");
+    memset(t + len, 0, (n - len) * sizeof *tape);
+    tape = t;
+    len = n;
+}
+"#;
+
+const GROW_LEFT: &str = r#"
+/* Grows the tape by at least `by` cells on the left, shifting every index */
+static void grow_left(size_t by) {
+    size_t extra = by > len ? by : len;
+    cell *t = calloc(len + extra, sizeof *tape);
+    if (!t)
+        fail(NULL, "out of memory");
+    memcpy(t + extra, tape, len * sizeof *tape);
+    free(tape);
+    tape = t;
+    len += extra;
+    p += extra;
+}
+"#;
+
+// The `cell_index` helper and the ops built on it
+const OPS: &str = r#"
+/* Index of the cell `d` away from the pointer */
+static inline size_t cell_index(ptrdiff_t d, const char *at) {
+    size_t target = p + (size_t)d;
+    if (target < len)
+        return target;
+    return outside(d, at);
+}
+
+/* The cell `d` away from the pointer, looked up before `tape` is touched as
+   growing the tape may move it */
+static inline cell *cell_at(ptrdiff_t d, const char *at) {
+    size_t i = cell_index(d, at);
+    return &tape[i];
+}
+
+/* Adds the current cell to the one `d` away and zeroes it, like `[->+<]` */
+static inline void add_move(ptrdiff_t d, const char *at) {
+    if (tape[p]) {
+        size_t dst = cell_index(d, at);
+        tape[dst] += tape[p];
+        tape[p] = 0;
+    }
+}
+
+/* Adds the current cell times `c` to the one `d` away, for a loop like `[->++<]` */
+static inline void mul_add(ptrdiff_t d, uint64_t c, const char *at) {
+    if (tape[p]) {
+        size_t dst = cell_index(d, at);
+        tape[dst] += (cell)(tape[p] * c);
+    }
+}
+
+static inline void mandel(ptrdiff_t x, ptrdiff_t n, const char *at) {
+    while (tape[p]) {
+        size_t src = cell_index(x, at);
+        if (tape[src]) {
+            size_t dst = cell_index(x + n, at);
+            tape[dst] += tape[src];
+            tape[src] = 0;
+        }
+        p = cell_index(-n, at);
+    }
+}
+"#;
+
+/// Writes a C program that runs `program` with cells of `width` bits and the
+/// tape size, tape policy and EOF policy in `config`. Runtime errors print the
+/// source position to stderr and exit with status 3, like `bfinterp` itself.
+pub fn emit<W: Write>(program: &Program, width: CellWidth, config: &Config, out: &mut W) -> io::Result<()> {
+    out.write_all(b"/* Generated by bfinterp */\n\n")?;
+    out.write_all(HEADER.as_bytes())?;
+    writeln!(out)?;
+    writeln!(out, "typedef uint{}_t cell;", width.bits())?;
+    writeln!(out, "#define TAPE_SIZE {}", cmp::max(config.tape_size, 1))?;
+    out.write_all(RUNTIME.as_bytes())?;
+    emit_outside(config.tape_policy, out)?;
+    out.write_all(OPS.as_bytes())?;
+    emit_read(config.eof_policy, out)?;
+
+    writeln!(out)?;
+    writeln!(out, "int main(void) {{")?;
+    writeln!(out, "    tape = calloc(len, sizeof *tape);")?;
+    writeln!(out, "    if (!tape)")?;
+    writeln!(out, "        fail(NULL, \"out of memory\");")?;
+    let mut emitter = Emitter { out, width, depth: 1 };
+    for (idx, instr) in program.instructions.iter().enumerate() {
+        let at = match program.span(idx) {
+            Some(span) => format!("\"{}\"", span.start),
+            None => "NULL".to_string(),
+        };
+        emitter.instruction(*instr, &at)?;
+    }
+    writeln!(out, "}}")
+}
+
+// The slow path of `cell_index`, applying the tape policy
+fn emit_outside<W: Write>(policy: TapePolicy, out: &mut W) -> io::Result<()> {
+    match policy {
+        TapePolicy::GrowRight => out.write_all(GROW_RIGHT.as_bytes())?,
+        TapePolicy::Grow => {
+            out.write_all(GROW_RIGHT.as_bytes())?;
+            out.write_all(GROW_LEFT.as_bytes())?;
+        }
+        TapePolicy::Wrap | TapePolicy::Error => {}
+    }
+    writeln!(out)?;
+    writeln!(out, "static size_t outside(ptrdiff_t d, const char *at) {{")?;
+    writeln!(out, "    ptrdiff_t target = (ptrdiff_t)p + d;")?;
+    match policy {
+        TapePolicy::Wrap => {
+            writeln!(out, "    (void)at;")?;
+            writeln!(out, "    target %= (ptrdiff_t)len;")?;
+            writeln!(out, "    return target < 0 ? (size_t)target + len : (size_t)target;")?;
+        }
+        TapePolicy::Error => {
+            writeln!(out, "    (void)target;")?;
+            writeln!(out, "    fail(at, \"tape pointer out of bounds\");")?;
+            writeln!(out, "    return 0;")?;
+        }
+        TapePolicy::GrowRight => {
+            writeln!(out, "    if (target < 0)")?;
+            writeln!(out, "        fail(at, \"tape pointer out of bounds\");")?;
+            writeln!(out, "    grow_right((size_t)target);")?;
+            writeln!(out, "    return (size_t)target;")?;
+        }
+        TapePolicy::Grow => {
+            writeln!(out, "    (void)at;")?;
+            writeln!(out, "    if (target >= 0) {{")?;
+            writeln!(out, "        grow_right((size_t)target);")?;
+            writeln!(out, "        return (size_t)target;")?;
+            writeln!(out, "    }}")?;
+            writeln!(out, "    grow_left((size_t)-target);")?;
+            writeln!(out, "    return (size_t)((ptrdiff_t)p + d);")?;
+        }
+    }
+    writeln!(out, "}}")
+}
+
+fn emit_read<W: Write>(policy: EofPolicy, out: &mut W) -> io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, "static inline void read_cell(void) {{")?;
+    writeln!(out, "    int c = getchar();")?;
+    writeln!(out, "    if (c != EOF)")?;
+    writeln!(out, "        tape[p] = (cell)c;")?;
+    match policy {
+        EofPolicy::Unchanged => {}
+        EofPolicy::Zero => {
+            writeln!(out, "    else")?;
+            writeln!(out, "        tape[p] = 0;")?;
+        }
+        EofPolicy::Max => {
+            writeln!(out, "    else")?;
+            writeln!(out, "        tape[p] = (cell)-1;")?;
+        }
+    }
+    writeln!(out, "}}")
+}
+
+// The cell `d` away from the pointer, as an lvalue
+fn cell(d: i32, at: &str) -> String {
+    match d {
+        0 => "tape[p]".to_string(),
+        _ => format!("*cell_at({}, {})", d, at),
+    }
+}
+
+// Keeps constants past `int` unsigned long long
+fn suffix(v: u64) -> &'static str {
+    if v > i32::MAX as u64 { "ULL" } else { "" }
+}
+
+struct Emitter<'a, W: Write> {
+    out: &'a mut W,
+    width: CellWidth,
+    depth: usize,
+}
+
+impl<'a, W: Write> Emitter<'a, W> {
+    fn line(&mut self, code: &str) -> io::Result<()> {
+        writeln!(self.out, "{:width$}{}", "", code, width = self.depth * 4)
+    }
+
+    fn instruction(&mut self, instr: Instruction, at: &str) -> io::Result<()> {
+        match instr {
+            Add(d, amount) => {
+                if let Some((op, v)) = add_operand(amount, self.width) {
+                    let cell = cell(d, at);
+                    self.line(&format!("{} {}= {}{};", cell, op, v, suffix(v)))?
+                }
+            }
+            Set(d, v) => {
+                let v = wrapped(v, self.width);
+                self.line(&format!("{} = {}{};", cell(d, at), v, suffix(v)))?
+            }
+            Jez(_) => {
+                self.line("while (tape[p]) {")?;
+                self.depth += 1;
+            }
+            Jnz(_) => {
+                self.depth -= 1;
+                self.line("}")?;
+            }
+            Right(n) => self.line(&format!("p = cell_index({}, {});", n, at))?,
+            Left(n) => self.line(&format!("p = cell_index(-{}, {});", n, at))?,
+            SearchRight(n) => self.line(&format!("while (tape[p]) p = cell_index({}, {});", n, at))?,
+            SearchLeft(n) => self.line(&format!("while (tape[p]) p = cell_index(-{}, {});", n, at))?,
+            AddMoveRight(n) => self.line(&format!("add_move({}, {});", n, at))?,
+            AddMoveLeft(n) => self.line(&format!("add_move(-{}, {});", n, at))?,
+            MulAdd(d, factor) => {
+                self.line(&format!("mul_add({}, {}ULL, {});", d, wrapped(factor, self.width), at))?
+            }
+            ZeroRight(n) | ZeroLeft(n) if n > 0 => {
+                let d = if let ZeroRight(_) = instr { "1" } else { "-1" };
+                self.line(&format!("for (int i = 0; i < {}; i++) {{", n))?;
+                self.line(&format!("    tape[p] = 0; p = cell_index({}, {});", d, at))?;
+                self.line("}")?
+            }
+            ZeroRight(_) | ZeroLeft(_) => {}
+            Mandel(x, n) => self.line(&format!("mandel({}, {}, {});", x, n, at))?,
+            Zero => self.line("tape[p] = 0;")?,
+            Write(d) => self.line(&format!("putchar((unsigned char){});", cell(d, at)))?,
+            Read => self.line("read_cell();")?,
+            Halt => {
+                self.line("if (fflush(stdout) == EOF || ferror(stdout))")?;
+                self.line("    fail(NULL, \"i/o error writing output\");")?;
+                self.line("return 0;")?
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,332 @@
+// Emits Rust source equivalent to an optimized Instruction list
+
+use super::{add_operand, wrapped};
+use super::super::cell::CellWidth;
+use super::super::common::{Instruction, Program};
+use super::super::config::{Config, EofPolicy, TapePolicy};
+use Instruction::*;
+
+use std::cmp;
+use std::io::{self, Write};
+
+// Everything after the cell type and tape size, up to the policy-specific parts
+const RUNTIME: &str = r#"
+/// Source position of a failing instruction as `line:column`, if known
+pub type Pos = Option<&'static str>;
+
+#[derive(Debug)]
+pub enum Error {
+    TapeOutOfBounds(Pos),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::TapeOutOfBounds(Some(at)) => write!(f, "{}: tape pointer out of bounds", at),
+            Error::TapeOutOfBounds(None) => write!(f, "tape pointer out of bounds"),
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+struct Tape {
+    cells: Vec<Cell>,
+    p: usize,
+}
+
+#[allow(dead_code)]
+impl Tape {
+    fn new() -> Self {
+        Tape {
+            cells: vec![0; TAPE_SIZE],
+            p: 0,
+        }
+    }
+
+    #[inline]
+    fn get(&self) -> Cell {
+        self.cells[self.p]
+    }
+
+    #[inline]
+    fn set(&mut self, v: Cell) {
+        self.cells[self.p] = v
+    }
+
+    #[inline]
+    fn add(&mut self, v: Cell) {
+        self.set(self.get().wrapping_add(v))
+    }
+
+    #[inline]
+    fn sub(&mut self, v: Cell) {
+        self.set(self.get().wrapping_sub(v))
+    }
+
+    /// The cell `d` away from the pointer
+    #[inline]
+    fn at(&mut self, d: isize, at: Pos) -> Result<&mut Cell, Error> {
+        let idx = self.index(d, at)?;
+        Ok(&mut self.cells[idx])
+    }
+
+    #[inline]
+    fn add_at(&mut self, d: isize, v: Cell, at: Pos) -> Result<(), Error> {
+        let cell = self.at(d, at)?;
+        *cell = cell.wrapping_add(v);
+        Ok(())
+    }
+
+    #[inline]
+    fn sub_at(&mut self, d: isize, v: Cell, at: Pos) -> Result<(), Error> {
+        let cell = self.at(d, at)?;
+        *cell = cell.wrapping_sub(v);
+        Ok(())
+    }
+
+    /// Index of the cell `d` away from the pointer
+    #[inline]
+    fn index(&mut self, d: isize, at: Pos) -> Result<usize, Error> {
+        let target = self.p.wrapping_add(d as usize);
+        if target < self.cells.len() {
+            Ok(target)
+        } else {
+            self.outside(d, at)
+        }
+    }
+
+    #[inline]
+    fn move_by(&mut self, d: isize, at: Pos) -> Result<(), Error> {
+        self.p = self.index(d, at)?;
+        Ok(())
+    }
+
+    /// Adds the current cell to the one `d` away and zeroes it, like `[->+<]`
+    #[inline]
+    fn add_move(&mut self, d: isize, at: Pos) -> Result<(), Error> {
+        let v = self.get();
+        if v != 0 {
+            let dst = self.index(d, at)?;
+            self.cells[dst] = self.cells[dst].wrapping_add(v);
+            self.set(0);
+        }
+        Ok(())
+    }
+
+    /// Adds the current cell times `c` to the one `d` away, for a loop like `[->++<]`
+    #[inline]
+    fn mul_add(&mut self, d: isize, c: Cell, at: Pos) -> Result<(), Error> {
+        let v = self.get();
+        if v != 0 {
+            let dst = self.index(d, at)?;
+            self.cells[dst] = self.cells[dst].wrapping_add(v.wrapping_mul(c));
+        }
+        Ok(())
+    }
+
+    fn mandel(&mut self, x: isize, n: isize, at: Pos) -> Result<(), Error> {
+        while self.get() != 0 {
+            let src = self.index(x, at)?;
+            let v = self.cells[src];
+            if v != 0 {
+                let dst = self.index(x + n, at)?;
+                self.cells[dst] = self.cells[dst].wrapping_add(v);
+                self.cells[src] = 0;
+            }
+            self.move_by(-n, at)?;
+        }
+        Ok(())
+    }
+"#;
+
+const GROW_RIGHT: &str = "\
+            let target = target as usize;
+            self.cells.resize(std::cmp::max(target + 1, len * 2), 0);
+            return Ok(target);
+";
+
+const MAIN: &str = r#"
+fn main() {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    let result = run(&mut stdin.lock(), &mut output);
+    let _ = output.flush();
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(3);
+    }
+}
+"#;
+
+/// Writes a Rust program that runs `program` with cells of `width` bits and
+/// the tape size, tape policy and EOF policy in `config`. Runtime errors print
+/// the source position to stderr and exit with status 3, like `bfinterp` itself.
+pub fn emit<W: Write>(program: &Program, width: CellWidth, config: &Config, out: &mut W) -> io::Result<()> {
+    emit_module(program, width, config, out)?;
+    out.write_all(MAIN.as_bytes())
+}
+
+/// Like `emit`, but without `main`, for `include!`ing generated code into a
+/// crate from a build script. The program runs through the generated
+/// `pub fn run<R: Read, W: Write>(input: &mut R, output: &mut W) -> Result<(), Error>`.
+pub fn emit_module<W: Write>(program: &Program, width: CellWidth, config: &Config, out: &mut W) -> io::Result<()> {
+    writeln!(out, "// Generated by bfinterp")?;
+    writeln!(out)?;
+    writeln!(out, "use std::fmt;")?;
+    writeln!(out, "use std::io::{{self, Read, Write}};")?;
+    writeln!(out)?;
+    writeln!(out, "pub type Cell = u{};", width.bits())?;
+    writeln!(out, "pub const TAPE_SIZE: usize = {};", cmp::max(config.tape_size, 1))?;
+    out.write_all(RUNTIME.as_bytes())?;
+    emit_outside(config.tape_policy, out)?;
+    emit_read(config.eof_policy, out)?;
+    writeln!(out, "}}")?;
+
+    writeln!(out)?;
+    writeln!(out, "/// Runs the program, reading `,` from `input` and writing `.` to `output`")?;
+    writeln!(out, "#[allow(unused_mut, unused_variables)]")?;
+    writeln!(out, "pub fn run<R: Read, W: Write>(input: &mut R, output: &mut W) -> Result<(), Error> {{")?;
+    writeln!(out, "    let mut t = Tape::new();")?;
+    let mut emitter = Emitter { out, width, depth: 1 };
+    for (idx, instr) in program.instructions.iter().enumerate() {
+        let at = match program.span(idx) {
+            Some(span) => format!("Some(\"{}\")", span.start),
+            None => "None".to_string(),
+        };
+        emitter.instruction(*instr, &at)?;
+    }
+    writeln!(out, "    Ok(())")?;
+    writeln!(out, "}}")
+}
+
+// The slow path of `Tape::index`, applying the tape policy
+fn emit_outside<W: Write>(policy: TapePolicy, out: &mut W) -> io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, "    #[cold]")?;
+    match policy {
+        TapePolicy::Wrap => {
+            writeln!(out, "    fn outside(&mut self, d: isize, _at: Pos) -> Result<usize, Error> {{")?;
+            writeln!(out, "        let len = self.cells.len() as isize;")?;
+            writeln!(out, "        Ok((self.p as isize + d).rem_euclid(len) as usize)")?;
+        }
+        TapePolicy::Error => {
+            writeln!(out, "    fn outside(&mut self, _d: isize, at: Pos) -> Result<usize, Error> {{")?;
+            writeln!(out, "        Err(Error::TapeOutOfBounds(at))")?;
+        }
+        TapePolicy::GrowRight | TapePolicy::Grow => {
+            let at = if policy == TapePolicy::Grow { "_at" } else { "at" };
+            writeln!(out, "    fn outside(&mut self, d: isize, {}: Pos) -> Result<usize, Error> {{", at)?;
+            writeln!(out, "        let len = self.cells.len();")?;
+            writeln!(out, "        let target = self.p as isize + d;")?;
+            writeln!(out, "        if target >= 0 {{")?;
+            out.write_all(GROW_RIGHT.as_bytes())?;
+            writeln!(out, "        }}")?;
+            if policy == TapePolicy::GrowRight {
+                writeln!(out, "        Err(Error::TapeOutOfBounds(at))")?;
+            } else {
+                writeln!(out, "        let extra = std::cmp::max(target.unsigned_abs(), len);")?;
+                writeln!(out, "        let mut cells = vec![0; extra];")?;
+                writeln!(out, "        cells.extend_from_slice(&self.cells);")?;
+                writeln!(out, "        self.cells = cells;")?;
+                writeln!(out, "        self.p += extra;")?;
+                writeln!(out, "        Ok((target + extra as isize) as usize)")?;
+            }
+        }
+    }
+    writeln!(out, "    }}")
+}
+
+fn emit_read<W: Write>(policy: EofPolicy, out: &mut W) -> io::Result<()> {
+    let eof = match policy {
+        EofPolicy::Unchanged => "{}",
+        EofPolicy::Zero => "self.set(0)",
+        EofPolicy::Max => "self.set(Cell::MAX)",
+    };
+    writeln!(out)?;
+    writeln!(out, "    fn read<R: Read>(&mut self, input: &mut R) -> Result<(), Error> {{")?;
+    writeln!(out, "        let mut b = [0u8; 1];")?;
+    writeln!(out, "        match input.read(&mut b)? {{")?;
+    writeln!(out, "            0 => {},", eof)?;
+    writeln!(out, "            _ => self.set(Cell::from(b[0])),")?;
+    writeln!(out, "        }}")?;
+    writeln!(out, "        Ok(())")?;
+    writeln!(out, "    }}")
+}
+
+struct Emitter<'a, W: Write> {
+    out: &'a mut W,
+    width: CellWidth,
+    depth: usize,
+}
+
+impl<'a, W: Write> Emitter<'a, W> {
+    fn line(&mut self, code: &str) -> io::Result<()> {
+        writeln!(self.out, "{:width$}{}", "", code, width = self.depth * 4)
+    }
+
+    fn instruction(&mut self, instr: Instruction, at: &str) -> io::Result<()> {
+        match instr {
+            Add(0, amount) => match add_operand(amount, self.width) {
+                Some(('-', v)) => self.line(&format!("t.sub({});", v))?,
+                Some((_, v)) => self.line(&format!("t.add({});", v))?,
+                None => {}
+            },
+            Add(d, amount) => match add_operand(amount, self.width) {
+                Some(('-', v)) => self.line(&format!("t.sub_at({}, {}, {})?;", d, v, at))?,
+                Some((_, v)) => self.line(&format!("t.add_at({}, {}, {})?;", d, v, at))?,
+                None => {}
+            },
+            Set(0, v) => self.line(&format!("t.set({});", wrapped(v, self.width)))?,
+            Set(d, v) => self.line(&format!("*t.at({}, {})? = {};", d, at, wrapped(v, self.width)))?,
+            Jez(_) => {
+                self.line("while t.get() != 0 {")?;
+                self.depth += 1;
+            }
+            Jnz(_) => {
+                self.depth -= 1;
+                self.line("}")?;
+            }
+            Right(n) => self.line(&format!("t.move_by({}, {})?;", n, at))?,
+            Left(n) => self.line(&format!("t.move_by(-{}, {})?;", n, at))?,
+            SearchRight(n) => self.line(&format!("while t.get() != 0 {{ t.move_by({}, {})?; }}", n, at))?,
+            SearchLeft(n) => self.line(&format!("while t.get() != 0 {{ t.move_by(-{}, {})?; }}", n, at))?,
+            AddMoveRight(n) => self.line(&format!("t.add_move({}, {})?;", n, at))?,
+            AddMoveLeft(n) => self.line(&format!("t.add_move(-{}, {})?;", n, at))?,
+            MulAdd(d, factor) => {
+                self.line(&format!("t.mul_add({}, {}, {})?;", d, wrapped(factor, self.width), at))?
+            }
+            ZeroRight(n) | ZeroLeft(n) if n > 0 => {
+                let d = if let ZeroRight(_) = instr { 1 } else { -1 };
+                self.line(&format!("for _ in 0..{} {{", n))?;
+                self.line("    t.set(0);")?;
+                self.line(&format!("    t.move_by({}, {})?;", d, at))?;
+                self.line("}")?
+            }
+            ZeroRight(_) | ZeroLeft(_) => {}
+            Mandel(x, n) => self.line(&format!("t.mandel({}, {}, {})?;", x, n, at))?,
+            Zero => self.line("t.set(0);")?,
+            Write(d) => {
+                let cell = match d {
+                    0 => "t.get()".to_string(),
+                    _ => format!("*t.at({}, {})?", d, at),
+                };
+                let cast = if self.width == CellWidth::U8 { "" } else { " as u8" };
+                self.line(&format!("output.write_all(&[{}{}])?;", cell, cast))?
+            }
+            Read => self.line("t.read(input)?;")?,
+            // The caller closes the function after the last instruction
+            Halt => {}
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,956 @@
+// Lowers an optimized Instruction list to a WebAssembly module
+
+use super::super::cell::CellWidth;
+use super::super::common::{Instruction, Program};
+use super::super::config::{Config, EofPolicy, TapePolicy};
+
+use std::io::{self, Write};
+
+const PAGE_SIZE: u64 = 65536;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ValType {
+    I32,
+    I64,
+}
+
+impl ValType {
+    fn name(self) -> &'static str {
+        match self {
+            ValType::I32 => "i32",
+            ValType::I64 => "i64",
+        }
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            ValType::I32 => 0x7f,
+            ValType::I64 => 0x7e,
+        }
+    }
+}
+
+// Every function in the module, in index order. Imports come first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Func {
+    Read,
+    Write,
+    Index,
+    Outside,
+    AddMove,
+    MulAdd,
+    Mandel,
+    ZeroRun,
+    ReadCell,
+    Run,
+}
+
+const IMPORTS: usize = 2;
+
+impl Func {
+    fn name(self) -> &'static str {
+        match self {
+            Func::Read => "read",
+            Func::Write => "write",
+            Func::Index => "index",
+            Func::Outside => "outside",
+            Func::AddMove => "add_move",
+            Func::MulAdd => "mul_add",
+            Func::Mandel => "mandel",
+            Func::ZeroRun => "zero_run",
+            Func::ReadCell => "read_cell",
+            Func::Run => "run",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Global {
+    // The pointer, as a cell index
+    P,
+    // Tape length in cells
+    Len,
+    Fault,
+}
+
+impl Global {
+    fn name(self) -> &'static str {
+        match self {
+            Global::P => "p",
+            Global::Len => "len",
+            Global::Fault => "fault",
+        }
+    }
+}
+
+// Memory access widths: `i32.load8_u`/`i32.store8` and so on up to `i64`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl Access {
+    fn align(self) -> u32 {
+        match self {
+            Access::U8 => 0,
+            Access::U16 => 1,
+            Access::U32 => 2,
+            Access::U64 => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Unreachable,
+    Block,
+    Loop,
+    If,
+    Else,
+    End,
+    Br(u32),
+    BrIf(u32),
+    Return,
+    Call(Func),
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(Global),
+    GlobalSet(Global),
+    Load(Access),
+    Store(Access),
+    MemorySize,
+    MemoryGrow,
+    I32Const(i32),
+    I64Const(i64),
+    I32Eqz,
+    I32Eq,
+    I32LtS,
+    I32LtU,
+    I32GtS,
+    I32GtU,
+    I32GeS,
+    I32Add,
+    I32Sub,
+    I32And,
+    I32Shl,
+    I32ShrU,
+    I32Mul,
+    I32RemS,
+    I64Eqz,
+    I64Add,
+    I64Mul,
+    I32WrapI64,
+    I64ExtendI32U,
+}
+
+impl Op {
+    fn text(self) -> String {
+        match self {
+            Op::Unreachable => "unreachable".into(),
+            Op::Block => "block".into(),
+            Op::Loop => "loop".into(),
+            Op::If => "if".into(),
+            Op::Else => "else".into(),
+            Op::End => "end".into(),
+            Op::Br(depth) => format!("br {}", depth),
+            Op::BrIf(depth) => format!("br_if {}", depth),
+            Op::Return => "return".into(),
+            Op::Call(f) => format!("call ${}", f.name()),
+            Op::Select => "select".into(),
+            Op::LocalGet(idx) => format!("local.get {}", idx),
+            Op::LocalSet(idx) => format!("local.set {}", idx),
+            Op::LocalTee(idx) => format!("local.tee {}", idx),
+            Op::GlobalGet(g) => format!("global.get ${}", g.name()),
+            Op::GlobalSet(g) => format!("global.set ${}", g.name()),
+            Op::Load(Access::U8) => "i32.load8_u".into(),
+            Op::Load(Access::U16) => "i32.load16_u".into(),
+            Op::Load(Access::U32) => "i32.load".into(),
+            Op::Load(Access::U64) => "i64.load".into(),
+            Op::Store(Access::U8) => "i32.store8".into(),
+            Op::Store(Access::U16) => "i32.store16".into(),
+            Op::Store(Access::U32) => "i32.store".into(),
+            Op::Store(Access::U64) => "i64.store".into(),
+            Op::MemorySize => "memory.size".into(),
+            Op::MemoryGrow => "memory.grow".into(),
+            Op::I32Const(v) => format!("i32.const {}", v),
+            Op::I64Const(v) => format!("i64.const {}", v),
+            Op::I32Eqz => "i32.eqz".into(),
+            Op::I32Eq => "i32.eq".into(),
+            Op::I32LtS => "i32.lt_s".into(),
+            Op::I32LtU => "i32.lt_u".into(),
+            Op::I32GtS => "i32.gt_s".into(),
+            Op::I32GtU => "i32.gt_u".into(),
+            Op::I32GeS => "i32.ge_s".into(),
+            Op::I32Add => "i32.add".into(),
+            Op::I32Sub => "i32.sub".into(),
+            Op::I32And => "i32.and".into(),
+            Op::I32Shl => "i32.shl".into(),
+            Op::I32ShrU => "i32.shr_u".into(),
+            Op::I32Mul => "i32.mul".into(),
+            Op::I32RemS => "i32.rem_s".into(),
+            Op::I64Eqz => "i64.eqz".into(),
+            Op::I64Add => "i64.add".into(),
+            Op::I64Mul => "i64.mul".into(),
+            Op::I32WrapI64 => "i32.wrap_i64".into(),
+            Op::I64ExtendI32U => "i64.extend_i32_u".into(),
+        }
+    }
+
+    fn encode(self, out: &mut Vec<u8>) {
+        match self {
+            Op::Unreachable => out.push(0x00),
+            // Blocks never produce values here, so their type is always empty
+            Op::Block => out.extend(&[0x02, 0x40]),
+            Op::Loop => out.extend(&[0x03, 0x40]),
+            Op::If => out.extend(&[0x04, 0x40]),
+            Op::Else => out.push(0x05),
+            Op::End => out.push(0x0b),
+            Op::Br(depth) => {
+                out.push(0x0c);
+                uleb(out, depth as u64)
+            }
+            Op::BrIf(depth) => {
+                out.push(0x0d);
+                uleb(out, depth as u64)
+            }
+            Op::Return => out.push(0x0f),
+            Op::Call(f) => {
+                out.push(0x10);
+                uleb(out, f as u64)
+            }
+            Op::Select => out.push(0x1b),
+            Op::LocalGet(idx) => {
+                out.push(0x20);
+                uleb(out, idx as u64)
+            }
+            Op::LocalSet(idx) => {
+                out.push(0x21);
+                uleb(out, idx as u64)
+            }
+            Op::LocalTee(idx) => {
+                out.push(0x22);
+                uleb(out, idx as u64)
+            }
+            Op::GlobalGet(g) => {
+                out.push(0x23);
+                uleb(out, g as u64)
+            }
+            Op::GlobalSet(g) => {
+                out.push(0x24);
+                uleb(out, g as u64)
+            }
+            Op::Load(access) | Op::Store(access) => {
+                out.push(match (self, access) {
+                    (Op::Load(_), Access::U8) => 0x2d,
+                    (Op::Load(_), Access::U16) => 0x2f,
+                    (Op::Load(_), Access::U32) => 0x28,
+                    (Op::Load(_), Access::U64) => 0x29,
+                    (_, Access::U8) => 0x3a,
+                    (_, Access::U16) => 0x3b,
+                    (_, Access::U32) => 0x36,
+                    (_, Access::U64) => 0x37,
+                });
+                // Natural alignment, no offset
+                uleb(out, access.align() as u64);
+                uleb(out, 0)
+            }
+            Op::MemorySize => out.extend(&[0x3f, 0x00]),
+            Op::MemoryGrow => out.extend(&[0x40, 0x00]),
+            Op::I32Const(v) => {
+                out.push(0x41);
+                sleb(out, v as i64)
+            }
+            Op::I64Const(v) => {
+                out.push(0x42);
+                sleb(out, v)
+            }
+            Op::I32Eqz => out.push(0x45),
+            Op::I32Eq => out.push(0x46),
+            Op::I32LtS => out.push(0x48),
+            Op::I32LtU => out.push(0x49),
+            Op::I32GtS => out.push(0x4a),
+            Op::I32GtU => out.push(0x4b),
+            Op::I32GeS => out.push(0x4e),
+            Op::I32Add => out.push(0x6a),
+            Op::I32Sub => out.push(0x6b),
+            Op::I32And => out.push(0x71),
+            Op::I32Shl => out.push(0x74),
+            Op::I32ShrU => out.push(0x76),
+            Op::I32Mul => out.push(0x6c),
+            Op::I32RemS => out.push(0x6f),
+            Op::I64Eqz => out.push(0x50),
+            Op::I64Add => out.push(0x7c),
+            Op::I64Mul => out.push(0x7e),
+            Op::I32WrapI64 => out.push(0xa7),
+            Op::I64ExtendI32U => out.push(0xad),
+        }
+    }
+}
+
+fn uleb(out: &mut Vec<u8>, mut v: u64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut v: i64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        let done = (v == 0 && byte & 0x40 == 0) || (v == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn section(out: &mut Vec<u8>, id: u8, contents: Vec<u8>) {
+    out.push(id);
+    uleb(out, contents.len() as u64);
+    out.extend(contents);
+}
+
+fn name(out: &mut Vec<u8>, s: &str) {
+    uleb(out, s.len() as u64);
+    out.extend(s.as_bytes());
+}
+
+struct Function {
+    func: Func,
+    params: Vec<ValType>,
+    results: Vec<ValType>,
+    locals: Vec<ValType>,
+    body: Vec<Op>,
+}
+
+// Builds function bodies, knowing how to reach and do arithmetic on cells
+struct Body {
+    ops: Vec<Op>,
+    width: CellWidth,
+}
+
+impl Body {
+    fn new(width: CellWidth) -> Self {
+        Self { ops: Vec::new(), width }
+    }
+
+    fn ops(&mut self, ops: &[Op]) -> &mut Self {
+        self.ops.extend_from_slice(ops);
+        self
+    }
+
+    fn access(&self) -> Access {
+        match self.width {
+            CellWidth::U8 => Access::U8,
+            CellWidth::U16 => Access::U16,
+            CellWidth::U32 => Access::U32,
+            CellWidth::U64 => Access::U64,
+        }
+    }
+
+    fn value_type(&self) -> ValType {
+        match self.width {
+            CellWidth::U64 => ValType::I64,
+            _ => ValType::I32,
+        }
+    }
+
+    // Cell index on the stack to its byte address
+    fn addr(&mut self) -> &mut Self {
+        let shift = self.access().align();
+        if shift > 0 {
+            self.ops(&[Op::I32Const(shift as i32), Op::I32Shl]);
+        }
+        self
+    }
+
+    // Address of the cell under the pointer
+    fn current(&mut self) -> &mut Self {
+        self.ops(&[Op::GlobalGet(Global::P)]).addr()
+    }
+
+    // Address of the cell `d` away from the pointer, for instruction `at`
+    fn cell_at(&mut self, d: i32, at: Op) -> &mut Self {
+        match d {
+            0 => self.current(),
+            _ => self.ops(&[Op::I32Const(d), at, Op::Call(Func::Index)]).addr(),
+        }
+    }
+
+    fn load(&mut self) -> &mut Self {
+        let access = self.access();
+        self.ops(&[Op::Load(access)])
+    }
+
+    fn store(&mut self) -> &mut Self {
+        let access = self.access();
+        self.ops(&[Op::Store(access)])
+    }
+
+    fn constant(&mut self, v: i64) -> &mut Self {
+        match self.value_type() {
+            ValType::I32 => self.ops(&[Op::I32Const(v as i32)]),
+            ValType::I64 => self.ops(&[Op::I64Const(v)]),
+        }
+    }
+
+    fn add(&mut self) -> &mut Self {
+        match self.value_type() {
+            ValType::I32 => self.ops(&[Op::I32Add]),
+            ValType::I64 => self.ops(&[Op::I64Add]),
+        }
+    }
+
+    fn mul(&mut self) -> &mut Self {
+        match self.value_type() {
+            ValType::I32 => self.ops(&[Op::I32Mul]),
+            ValType::I64 => self.ops(&[Op::I64Mul]),
+        }
+    }
+
+    fn eqz(&mut self) -> &mut Self {
+        match self.value_type() {
+            ValType::I32 => self.ops(&[Op::I32Eqz]),
+            ValType::I64 => self.ops(&[Op::I64Eqz]),
+        }
+    }
+
+    // Opens a loop that runs while the current cell is not zero
+    fn while_nonzero(&mut self) -> &mut Self {
+        self.ops(&[Op::Block, Op::Loop]).current().load().eqz().ops(&[Op::BrIf(1)])
+    }
+
+    fn end_while(&mut self) -> &mut Self {
+        self.ops(&[Op::Br(0), Op::End, Op::End])
+    }
+
+    // Moves the pointer by the i32 on the stack, reporting failure at `at`
+    fn move_by(&mut self, at: Op) -> &mut Self {
+        self.ops(&[at, Op::Call(Func::Index), Op::GlobalSet(Global::P)])
+    }
+
+    // Makes room in memory for the number of cells in local `cells`, using
+    // local `tmp` and failing at local `at`
+    fn reserve(&mut self, cells: u32, tmp: u32, at: u32) -> &mut Self {
+        self.ops(&[Op::LocalGet(cells)]).addr().ops(&[
+            Op::I32Const(PAGE_SIZE as i32 - 1),
+            Op::I32Add,
+            Op::I32Const(16),
+            Op::I32ShrU,
+            Op::MemorySize,
+            Op::I32Sub,
+            Op::LocalTee(tmp),
+            Op::I32Const(0),
+            Op::I32GtS,
+            Op::If,
+            Op::LocalGet(tmp),
+            Op::MemoryGrow,
+            Op::I32Const(-1),
+            Op::I32Eq,
+            Op::If,
+        ])
+        .fail(at)
+        .ops(&[Op::End, Op::End])
+    }
+
+    fn fail(&mut self, at: u32) -> &mut Self {
+        self.ops(&[Op::LocalGet(at), Op::GlobalSet(Global::Fault), Op::Unreachable])
+    }
+}
+
+struct Module {
+    pages: u64,
+    tape_size: i32,
+    funcs: Vec<Function>,
+}
+
+impl Module {
+    fn new(program: &Program, width: CellWidth, config: &Config) -> io::Result<Self> {
+        let tape_size = config.tape_size.max(1);
+        let bytes = tape_size as u64 * (width.bits() as u64 / 8);
+        if bytes > i32::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "tape too large for WebAssembly"));
+        }
+        let cell = match width {
+            CellWidth::U64 => ValType::I64,
+            _ => ValType::I32,
+        };
+        use ValType::I32;
+        let function = |func, params: &[ValType], results: &[ValType], locals: &[ValType], body: Body| Function {
+            func,
+            params: params.to_vec(),
+            results: results.to_vec(),
+            locals: locals.to_vec(),
+            body: body.ops,
+        };
+        let funcs = vec![
+            function(Func::Index, &[I32, I32], &[I32], &[I32], index(width)),
+            function(Func::Outside, &[I32, I32], &[I32], &[I32, I32, I32], outside(width, config.tape_policy)),
+            function(Func::AddMove, &[I32, I32], &[], &[cell, I32], add_move(width)),
+            function(Func::MulAdd, &[I32, cell, I32], &[], &[cell, I32], mul_add(width)),
+            function(Func::Mandel, &[I32, I32, I32], &[], &[I32, cell, I32], mandel(width)),
+            function(Func::ZeroRun, &[I32, I32, I32], &[], &[], zero_run(width)),
+            function(Func::ReadCell, &[], &[], &[I32], read_cell(width, config.eof_policy)),
+            function(Func::Run, &[], &[], &[I32], run(program, width)),
+        ];
+        debug_assert!(funcs.iter().enumerate().all(|(idx, f)| f.func as usize == idx + IMPORTS));
+        Ok(Self {
+            pages: bytes.div_ceil(PAGE_SIZE).max(1),
+            tape_size: tape_size as i32,
+            funcs,
+        })
+    }
+
+    fn write_text<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "(module")?;
+        writeln!(out, "  (import \"env\" \"read\" (func $read (result i32)))")?;
+        writeln!(out, "  (import \"env\" \"write\" (func $write (param i32)))")?;
+        writeln!(out, "  (memory (export \"memory\") {})", self.pages)?;
+        writeln!(out, "  (global $p (mut i32) (i32.const 0))")?;
+        writeln!(out, "  (global $len (mut i32) (i32.const {}))", self.tape_size)?;
+        writeln!(out, "  (global $fault (export \"fault\") (mut i32) (i32.const -1))")?;
+        for f in &self.funcs {
+            write!(out, "  (func ${}", f.func.name())?;
+            if f.func == Func::Run {
+                write!(out, " (export \"run\")")?;
+            }
+            for (kind, types) in &[("param", &f.params), ("result", &f.results), ("local", &f.locals)] {
+                if !types.is_empty() {
+                    write!(out, " ({}", kind)?;
+                    for t in types.iter() {
+                        write!(out, " {}", t.name())?;
+                    }
+                    write!(out, ")")?;
+                }
+            }
+            writeln!(out)?;
+            let mut depth = 2;
+            for op in &f.body {
+                if let Op::End | Op::Else = op {
+                    depth -= 1;
+                }
+                writeln!(out, "{:width$}{}", "", op.text(), width = depth * 2)?;
+                if let Op::Block | Op::Loop | Op::If | Op::Else = op {
+                    depth += 1;
+                }
+            }
+            writeln!(out, "  )")?;
+        }
+        writeln!(out, ")")
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut types: Vec<(Vec<ValType>, Vec<ValType>)> = vec![(vec![], vec![ValType::I32]), (vec![ValType::I32], vec![])];
+        let mut type_of = |params: &Vec<ValType>, results: &Vec<ValType>| {
+            let sig = (params.clone(), results.clone());
+            match types.iter().position(|t| *t == sig) {
+                Some(idx) => idx,
+                None => {
+                    types.push(sig);
+                    types.len() - 1
+                }
+            }
+        };
+        let func_types: Vec<usize> = self.funcs.iter().map(|f| type_of(&f.params, &f.results)).collect();
+
+        let mut out = b"\0asm\x01\0\0\0".to_vec();
+
+        let mut s = Vec::new();
+        uleb(&mut s, types.len() as u64);
+        for (params, results) in &types {
+            s.push(0x60);
+            for list in &[params, results] {
+                uleb(&mut s, list.len() as u64);
+                s.extend(list.iter().map(|t| t.code()));
+            }
+        }
+        section(&mut out, 1, s);
+
+        let mut s = Vec::new();
+        uleb(&mut s, IMPORTS as u64);
+        for (idx, f) in [Func::Read, Func::Write].iter().enumerate() {
+            name(&mut s, "env");
+            name(&mut s, f.name());
+            s.push(0x00);
+            uleb(&mut s, idx as u64);
+        }
+        section(&mut out, 2, s);
+
+        let mut s = Vec::new();
+        uleb(&mut s, func_types.len() as u64);
+        for t in &func_types {
+            uleb(&mut s, *t as u64);
+        }
+        section(&mut out, 3, s);
+
+        let mut s = vec![1, 0x00];
+        uleb(&mut s, self.pages);
+        section(&mut out, 5, s);
+
+        let mut s = vec![3];
+        for init in &[0, self.tape_size, -1] {
+            s.extend(&[ValType::I32.code(), 0x01]);
+            Op::I32Const(*init).encode(&mut s);
+            Op::End.encode(&mut s);
+        }
+        section(&mut out, 6, s);
+
+        let mut s = vec![3];
+        for (field, kind, idx) in &[("run", 0x00, Func::Run as u64), ("memory", 0x02, 0), ("fault", 0x03, Global::Fault as u64)] {
+            name(&mut s, field);
+            s.push(*kind);
+            uleb(&mut s, *idx);
+        }
+        section(&mut out, 7, s);
+
+        let mut s = Vec::new();
+        uleb(&mut s, self.funcs.len() as u64);
+        for f in &self.funcs {
+            let mut code = Vec::new();
+            // Locals are declared as runs of the same type
+            let mut runs: Vec<(u32, ValType)> = Vec::new();
+            for t in &f.locals {
+                match runs.last_mut() {
+                    Some((n, last)) if last == t => *n += 1,
+                    _ => runs.push((1, *t)),
+                }
+            }
+            uleb(&mut code, runs.len() as u64);
+            for (n, t) in runs {
+                uleb(&mut code, n as u64);
+                code.push(t.code());
+            }
+            for op in &f.body {
+                op.encode(&mut code);
+            }
+            Op::End.encode(&mut code);
+            uleb(&mut s, code.len() as u64);
+            s.extend(code);
+        }
+        section(&mut out, 10, s);
+        out
+    }
+}
+
+// (d, at) -> index of the cell `d` away from the pointer
+fn index(width: CellWidth) -> Body {
+    let mut b = Body::new(width);
+    b.ops(&[
+        Op::GlobalGet(Global::P),
+        Op::LocalGet(0),
+        Op::I32Add,
+        Op::LocalTee(2),
+        Op::GlobalGet(Global::Len),
+        Op::I32LtU,
+        Op::If,
+        Op::LocalGet(2),
+        Op::Return,
+        Op::End,
+        Op::LocalGet(0),
+        Op::LocalGet(1),
+        Op::Call(Func::Outside),
+    ]);
+    b
+}
+
+// (d, at) -> the slow path of `index`, applying the tape policy
+fn outside(width: CellWidth, policy: TapePolicy) -> Body {
+    let (d, at, target, n, tmp) = (0, 1, 2, 3, 4);
+    let mut b = Body::new(width);
+    b.ops(&[Op::GlobalGet(Global::P), Op::LocalGet(d), Op::I32Add, Op::LocalSet(target)]);
+    match policy {
+        TapePolicy::Wrap => {
+            b.ops(&[
+                Op::LocalGet(target),
+                Op::GlobalGet(Global::Len),
+                Op::I32RemS,
+                Op::LocalTee(target),
+                Op::I32Const(0),
+                Op::I32LtS,
+                Op::If,
+                Op::LocalGet(target),
+                Op::GlobalGet(Global::Len),
+                Op::I32Add,
+                Op::LocalSet(target),
+                Op::End,
+                Op::LocalGet(target),
+            ]);
+        }
+        TapePolicy::Error => {
+            b.fail(at);
+        }
+        TapePolicy::GrowRight | TapePolicy::Grow => {
+            // Right: the length becomes max(target + 1, len * 2)
+            b.ops(&[Op::LocalGet(target), Op::I32Const(0), Op::I32GeS, Op::If]);
+            b.ops(&[
+                Op::LocalGet(target),
+                Op::I32Const(1),
+                Op::I32Add,
+                Op::LocalTee(n),
+                Op::GlobalGet(Global::Len),
+                Op::I32Const(1),
+                Op::I32Shl,
+                Op::LocalTee(tmp),
+                Op::LocalGet(n),
+                Op::LocalGet(tmp),
+                Op::I32GtU,
+                Op::Select,
+                Op::LocalSet(n),
+            ]);
+            b.reserve(n, tmp, at);
+            b.ops(&[Op::LocalGet(n), Op::GlobalSet(Global::Len), Op::LocalGet(target), Op::Return, Op::End]);
+            if policy == TapePolicy::GrowRight {
+                b.fail(at);
+                return b;
+            }
+            // Left: prepend max(-target, len) cells, moving the tape up
+            b.ops(&[
+                Op::I32Const(0),
+                Op::LocalGet(target),
+                Op::I32Sub,
+                Op::LocalTee(n),
+                Op::GlobalGet(Global::Len),
+                Op::LocalGet(n),
+                Op::GlobalGet(Global::Len),
+                Op::I32GtU,
+                Op::Select,
+                Op::LocalTee(n),
+                Op::GlobalGet(Global::Len),
+                Op::I32Add,
+                Op::LocalSet(tmp),
+            ]);
+            b.reserve(tmp, tmp, at);
+            // Copy bytes from the top down so nothing is overwritten early
+            b.ops(&[Op::GlobalGet(Global::Len)]).addr().ops(&[Op::LocalSet(tmp)]);
+            b.ops(&[
+                Op::Block,
+                Op::Loop,
+                Op::LocalGet(tmp),
+                Op::I32Eqz,
+                Op::BrIf(1),
+                Op::LocalGet(tmp),
+                Op::I32Const(1),
+                Op::I32Sub,
+                Op::LocalTee(tmp),
+                Op::LocalGet(n),
+            ]);
+            b.addr().ops(&[
+                Op::I32Add,
+                Op::LocalGet(tmp),
+                Op::Load(Access::U8),
+                Op::Store(Access::U8),
+                Op::Br(0),
+                Op::End,
+                Op::End,
+            ]);
+            b.ops(&[Op::LocalGet(n)]).addr().ops(&[Op::LocalSet(tmp)]);
+            b.ops(&[
+                Op::Block,
+                Op::Loop,
+                Op::LocalGet(tmp),
+                Op::I32Eqz,
+                Op::BrIf(1),
+                Op::LocalGet(tmp),
+                Op::I32Const(1),
+                Op::I32Sub,
+                Op::LocalTee(tmp),
+                Op::I32Const(0),
+                Op::Store(Access::U8),
+                Op::Br(0),
+                Op::End,
+                Op::End,
+            ]);
+            b.ops(&[
+                Op::GlobalGet(Global::Len),
+                Op::LocalGet(n),
+                Op::I32Add,
+                Op::GlobalSet(Global::Len),
+                Op::GlobalGet(Global::P),
+                Op::LocalGet(n),
+                Op::I32Add,
+                Op::GlobalSet(Global::P),
+                Op::LocalGet(target),
+                Op::LocalGet(n),
+                Op::I32Add,
+            ]);
+        }
+    }
+    b
+}
+
+// (d, at): adds the current cell to the one `d` away and zeroes it, like `[->+<]`
+fn add_move(width: CellWidth) -> Body {
+    let (d, at, v, dst) = (0, 1, 2, 3);
+    let mut b = Body::new(width);
+    b.current().load().ops(&[Op::LocalTee(v)]).eqz().ops(&[Op::If, Op::Return, Op::End]);
+    b.ops(&[Op::LocalGet(d), Op::LocalGet(at), Op::Call(Func::Index)]).addr();
+    b.ops(&[Op::LocalTee(dst), Op::LocalGet(dst)]).load().ops(&[Op::LocalGet(v)]).add().store();
+    b.current().constant(0).store();
+    b
+}
+
+// (d, c, at): adds the current cell times `c` to the one `d` away
+fn mul_add(width: CellWidth) -> Body {
+    let (d, c, at, v, dst) = (0, 1, 2, 3, 4);
+    let mut b = Body::new(width);
+    b.current().load().ops(&[Op::LocalTee(v)]).eqz().ops(&[Op::If, Op::Return, Op::End]);
+    b.ops(&[Op::LocalGet(d), Op::LocalGet(at), Op::Call(Func::Index)]).addr();
+    b.ops(&[Op::LocalTee(dst), Op::LocalGet(dst)]).load();
+    b.ops(&[Op::LocalGet(v), Op::LocalGet(c)]).mul().add().store();
+    b
+}
+
+// (x, n, at)
+fn mandel(width: CellWidth) -> Body {
+    let (x, n, at, src, v, dst) = (0, 1, 2, 3, 4, 5);
+    let mut b = Body::new(width);
+    b.while_nonzero();
+    b.ops(&[Op::LocalGet(x), Op::LocalGet(at), Op::Call(Func::Index), Op::LocalSet(src)]);
+    b.ops(&[Op::LocalGet(src)]).addr().load().ops(&[Op::LocalTee(v)]).eqz().ops(&[Op::I32Eqz, Op::If]);
+    b.ops(&[Op::LocalGet(x), Op::LocalGet(n), Op::I32Add, Op::LocalGet(at), Op::Call(Func::Index)]).addr();
+    b.ops(&[Op::LocalTee(dst), Op::LocalGet(dst)]).load().ops(&[Op::LocalGet(v)]).add().store();
+    b.ops(&[Op::LocalGet(src)]).addr().constant(0).store();
+    b.ops(&[Op::End, Op::I32Const(0), Op::LocalGet(n), Op::I32Sub]).move_by(Op::LocalGet(at));
+    b.end_while();
+    b
+}
+
+// (n, step, at): zeroes `n` cells, moving `step` after each
+fn zero_run(width: CellWidth) -> Body {
+    let (n, step, at) = (0, 1, 2);
+    let mut b = Body::new(width);
+    b.ops(&[Op::Block, Op::Loop, Op::LocalGet(n), Op::I32Eqz, Op::BrIf(1)]);
+    b.current().constant(0).store();
+    b.ops(&[Op::LocalGet(step)]).move_by(Op::LocalGet(at));
+    b.ops(&[Op::LocalGet(n), Op::I32Const(1), Op::I32Sub, Op::LocalSet(n)]);
+    b.ops(&[Op::Br(0), Op::End, Op::End]);
+    b
+}
+
+fn read_cell(width: CellWidth, policy: EofPolicy) -> Body {
+    let c = 0;
+    let mut b = Body::new(width);
+    b.ops(&[Op::Call(Func::Read), Op::LocalTee(c), Op::I32Const(0), Op::I32GeS, Op::If]);
+    b.current().ops(&[Op::LocalGet(c)]);
+    if width == CellWidth::U64 {
+        b.ops(&[Op::I64ExtendI32U]);
+    }
+    b.store();
+    match policy {
+        EofPolicy::Unchanged => {}
+        EofPolicy::Zero => {
+            b.ops(&[Op::Else]).current().constant(0).store();
+        }
+        EofPolicy::Max => {
+            b.ops(&[Op::Else]).current().constant(-1).store();
+        }
+    }
+    b.ops(&[Op::End]);
+    b
+}
+
+fn run(program: &Program, width: CellWidth) -> Body {
+    let a = 0;
+    let mut b = Body::new(width);
+    for (idx, instr) in program.instructions.iter().enumerate() {
+        let at = Op::I32Const(idx as i32);
+        match *instr {
+            Instruction::Add(d, amount) => {
+                b.cell_at(d, at).ops(&[Op::LocalTee(a), Op::LocalGet(a)]).load().constant(amount).add().store();
+            }
+            Instruction::Set(d, v) => {
+                b.cell_at(d, at).constant(v).store();
+            }
+            Instruction::Jez(_) => {
+                b.while_nonzero();
+            }
+            Instruction::Jnz(_) => {
+                b.end_while();
+            }
+            Instruction::Right(n) => {
+                b.ops(&[Op::I32Const(n as i32)]).move_by(at);
+            }
+            Instruction::Left(n) => {
+                b.ops(&[Op::I32Const(-(n as i32))]).move_by(at);
+            }
+            Instruction::SearchRight(n) => {
+                b.while_nonzero().ops(&[Op::I32Const(n as i32)]).move_by(at).end_while();
+            }
+            Instruction::SearchLeft(n) => {
+                b.while_nonzero().ops(&[Op::I32Const(-(n as i32))]).move_by(at).end_while();
+            }
+            Instruction::AddMoveRight(n) => {
+                b.ops(&[Op::I32Const(n as i32), at, Op::Call(Func::AddMove)]);
+            }
+            Instruction::AddMoveLeft(n) => {
+                b.ops(&[Op::I32Const(-(n as i32)), at, Op::Call(Func::AddMove)]);
+            }
+            Instruction::MulAdd(d, factor) => {
+                b.ops(&[Op::I32Const(d)]).constant(factor).ops(&[at, Op::Call(Func::MulAdd)]);
+            }
+            Instruction::ZeroRight(n) if n > 0 => {
+                b.ops(&[Op::I32Const(n as i32), Op::I32Const(1), at, Op::Call(Func::ZeroRun)]);
+            }
+            Instruction::ZeroLeft(n) if n > 0 => {
+                b.ops(&[Op::I32Const(n as i32), Op::I32Const(-1), at, Op::Call(Func::ZeroRun)]);
+            }
+            Instruction::ZeroRight(_) | Instruction::ZeroLeft(_) => {}
+            Instruction::Mandel(x, n) => {
+                b.ops(&[Op::I32Const(x as i32), Op::I32Const(n as i32), at, Op::Call(Func::Mandel)]);
+            }
+            Instruction::Zero => {
+                b.current().constant(0).store();
+            }
+            Instruction::Write(d) => {
+                b.cell_at(d, at).load();
+                match width {
+                    CellWidth::U8 => {}
+                    CellWidth::U64 => {
+                        b.ops(&[Op::I32WrapI64, Op::I32Const(0xff), Op::I32And]);
+                    }
+                    _ => {
+                        b.ops(&[Op::I32Const(0xff), Op::I32And]);
+                    }
+                }
+                b.ops(&[Op::Call(Func::Write)]);
+            }
+            Instruction::Read => {
+                b.ops(&[Op::Call(Func::ReadCell)]);
+            }
+            // The function ends after the last instruction
+            Instruction::Halt => {}
+        }
+    }
+    b
+}
+
+/// Writes a WebAssembly text module that runs `program` with cells of `width`
+/// bits and the tape size, tape policy and EOF policy in `config`.
+///
+/// The module imports `env.read: () -> i32`, returning the next input byte or
+/// -1 at end of input, and `env.write: (i32) -> ()`, taking an output byte. It
+/// exports `run: () -> ()` and its linear memory as `memory`, cell `i` being at
+/// byte `i * width / 8`. When the pointer leaves the tape and the tape policy
+/// makes that an error, `run` traps after storing the index of the failing
+/// instruction in the exported global `fault`, which is otherwise -1.
+pub fn emit_wat<W: Write>(program: &Program, width: CellWidth, config: &Config, out: &mut W) -> io::Result<()> {
+    Module::new(program, width, config)?.write_text(out)
+}
+
+/// Like `emit_wat`, but writes the binary encoding
+pub fn emit_wasm<W: Write>(program: &Program, width: CellWidth, config: &Config, out: &mut W) -> io::Result<()> {
+    out.write_all(&Module::new(program, width, config)?.encode())
+}
@@ -0,0 +1,331 @@
+// Little-endian encoding shared by snapshots and bytecode files
+
+use super::common::{Instruction, Position, Span};
+use super::config::{Config, EofPolicy, TapePolicy};
+use super::error::{Error, Result};
+use super::limits::Limits;
+use Instruction::*;
+
+// FNV-1a, 64-bit
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(FNV_OFFSET, |h, &b| (h ^ b as u64).wrapping_mul(FNV_PRIME))
+}
+
+/// Hash of the encoded instructions
+pub(crate) fn fingerprint(instructions: &[Instruction]) -> u64 {
+    let mut bytes = Vec::with_capacity(instructions.len() * 3);
+    for &instr in instructions {
+        put_instruction(&mut bytes, instr);
+    }
+    fnv1a(&bytes)
+}
+
+pub(crate) fn put_u8(out: &mut Vec<u8>, v: u8) {
+    out.push(v)
+}
+
+pub(crate) fn put_u16(out: &mut Vec<u8>, v: u16) {
+    out.extend_from_slice(&v.to_le_bytes())
+}
+
+pub(crate) fn put_u64(out: &mut Vec<u8>, v: u64) {
+    out.extend_from_slice(&v.to_le_bytes())
+}
+
+pub(crate) fn put_i32(out: &mut Vec<u8>, v: i32) {
+    out.extend_from_slice(&v.to_le_bytes())
+}
+
+pub(crate) fn put_i64(out: &mut Vec<u8>, v: i64) {
+    out.extend_from_slice(&v.to_le_bytes())
+}
+
+/// LEB128: seven bits a byte, low bits first, the high bit set on all but the last
+pub(crate) fn put_var(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8)
+}
+
+pub(crate) fn put_span(out: &mut Vec<u8>, span: Span) {
+    for pos in &[span.start, span.end] {
+        put_var(out, pos.offset as u64);
+        put_var(out, pos.line as u64);
+        put_var(out, pos.column as u64);
+    }
+}
+
+fn put_option(out: &mut Vec<u8>, v: Option<u64>) {
+    put_u8(out, v.is_some() as u8);
+    put_u64(out, v.unwrap_or(0));
+}
+
+/// Opcode byte followed by the operands
+pub(crate) fn put_instruction(out: &mut Vec<u8>, instr: Instruction) {
+    match instr {
+        Add(offset, n) => {
+            put_u8(out, 0);
+            put_i32(out, offset);
+            put_i64(out, n)
+        }
+        Jez(d) => {
+            put_u8(out, 1);
+            put_u16(out, d)
+        }
+        Jnz(d) => {
+            put_u8(out, 2);
+            put_u16(out, d)
+        }
+        Left(n) => {
+            put_u8(out, 3);
+            put_u16(out, n)
+        }
+        Right(n) => {
+            put_u8(out, 4);
+            put_u16(out, n)
+        }
+        SearchLeft(n) => {
+            put_u8(out, 5);
+            put_u16(out, n)
+        }
+        SearchRight(n) => {
+            put_u8(out, 6);
+            put_u16(out, n)
+        }
+        AddMoveRight(n) => {
+            put_u8(out, 7);
+            put_u16(out, n)
+        }
+        AddMoveLeft(n) => {
+            put_u8(out, 8);
+            put_u16(out, n)
+        }
+        ZeroRight(n) => {
+            put_u8(out, 9);
+            put_u16(out, n)
+        }
+        ZeroLeft(n) => {
+            put_u8(out, 10);
+            put_u16(out, n)
+        }
+        Mandel(x, n) => {
+            put_u8(out, 11);
+            put_u16(out, x);
+            put_u16(out, n)
+        }
+        Zero => put_u8(out, 12),
+        Write(offset) => {
+            put_u8(out, 13);
+            put_i32(out, offset)
+        }
+        Read => put_u8(out, 14),
+        Halt => put_u8(out, 15),
+        MulAdd(offset, factor) => {
+            put_u8(out, 16);
+            put_i32(out, offset);
+            put_i64(out, factor)
+        }
+        Set(offset, v) => {
+            put_u8(out, 17);
+            put_i32(out, offset);
+            put_i64(out, v)
+        }
+    }
+}
+
+pub(crate) fn put_config(out: &mut Vec<u8>, config: &Config) {
+    put_u64(out, config.tape_size as u64);
+    put_u8(
+        out,
+        match config.tape_policy {
+            TapePolicy::Wrap => 0,
+            TapePolicy::Error => 1,
+            TapePolicy::GrowRight => 2,
+            TapePolicy::Grow => 3,
+        },
+    );
+    put_u8(
+        out,
+        match config.eof_policy {
+            EofPolicy::Unchanged => 0,
+            EofPolicy::Zero => 1,
+            EofPolicy::Max => 2,
+        },
+    );
+    put_option(out, config.limits.steps);
+    put_option(out, config.limits.output);
+    put_option(out, config.limits.cells.map(|n| n as u64));
+}
+
+/// Reads back what the `put_*` functions wrote. Running out of bytes or
+/// finding a value that was never written is an `Error::Format`.
+pub(crate) struct Decoder<'a> {
+    bytes: &'a [u8],
+    what: &'static str,
+}
+
+impl<'a> Decoder<'a> {
+    /// Decodes `bytes`, naming them `what` in errors
+    pub fn new(bytes: &'a [u8], what: &'static str) -> Self {
+        Self { bytes, what }
+    }
+
+    pub fn error(&self, msg: &str) -> Error {
+        Error::Format(format!("{}: {}", self.what, msg))
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < n {
+            return Err(self.error("unexpected end of file"));
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let mut b = [0; 2];
+        b.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_le_bytes(b))
+    }
+
+    pub fn i32(&mut self) -> Result<i32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.bytes(4)?);
+        Ok(i32::from_le_bytes(b))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    pub fn i64(&mut self) -> Result<i64> {
+        Ok(self.u64()? as i64)
+    }
+
+    /// A `u64` that must fit in a `usize`
+    pub fn usize(&mut self) -> Result<usize> {
+        let v = self.u64()?;
+        if v > usize::MAX as u64 {
+            return Err(self.error("value too large for this platform"));
+        }
+        Ok(v as usize)
+    }
+
+    pub fn var(&mut self) -> Result<u64> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.u8()?;
+            v |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(self.error("integer too long"))
+    }
+
+    fn var_usize(&mut self) -> Result<usize> {
+        let v = self.var()?;
+        if v > usize::MAX as u64 {
+            return Err(self.error("value too large for this platform"));
+        }
+        Ok(v as usize)
+    }
+
+    pub fn span(&mut self) -> Result<Span> {
+        let mut pos = || {
+            Ok(Position {
+                offset: self.var_usize()?,
+                line: self.var_usize()?,
+                column: self.var_usize()?,
+            })
+        };
+        Ok(Span {
+            start: pos()?,
+            end: pos()?,
+        })
+    }
+
+    fn option(&mut self) -> Result<Option<u64>> {
+        let some = self.u8()?;
+        let v = self.u64()?;
+        match some {
+            0 => Ok(None),
+            1 => Ok(Some(v)),
+            _ => Err(self.error("corrupt optional value")),
+        }
+    }
+
+    pub fn instruction(&mut self) -> Result<Instruction> {
+        Ok(match self.u8()? {
+            0 => Add(self.i32()?, self.i64()?),
+            1 => Jez(self.u16()?),
+            2 => Jnz(self.u16()?),
+            3 => Left(self.u16()?),
+            4 => Right(self.u16()?),
+            5 => SearchLeft(self.u16()?),
+            6 => SearchRight(self.u16()?),
+            7 => AddMoveRight(self.u16()?),
+            8 => AddMoveLeft(self.u16()?),
+            9 => ZeroRight(self.u16()?),
+            10 => ZeroLeft(self.u16()?),
+            11 => Mandel(self.u16()?, self.u16()?),
+            12 => Zero,
+            13 => Write(self.i32()?),
+            14 => Read,
+            15 => Halt,
+            16 => MulAdd(self.i32()?, self.i64()?),
+            17 => Set(self.i32()?, self.i64()?),
+            op => return Err(self.error(&format!("unknown opcode {}", op))),
+        })
+    }
+
+    pub fn config(&mut self) -> Result<Config> {
+        let tape_size = self.usize()?;
+        let tape_policy = match self.u8()? {
+            0 => TapePolicy::Wrap,
+            1 => TapePolicy::Error,
+            2 => TapePolicy::GrowRight,
+            3 => TapePolicy::Grow,
+            _ => return Err(self.error("unknown tape policy")),
+        };
+        let eof_policy = match self.u8()? {
+            0 => EofPolicy::Unchanged,
+            1 => EofPolicy::Zero,
+            2 => EofPolicy::Max,
+            _ => return Err(self.error("unknown EOF policy")),
+        };
+        let limits = Limits {
+            steps: self.option()?,
+            output: self.option()?,
+            cells: self.option()?.map(|n| n as usize),
+        };
+        Ok(Config::new()
+            .tape_size(tape_size)
+            .tape_policy(tape_policy)
+            .eof_policy(eof_policy)
+            .limits(limits))
+    }
+
+    /// Fails unless every byte has been read
+    pub fn finish(&self) -> Result<()> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(self.error("trailing bytes"))
+        }
+    }
+}
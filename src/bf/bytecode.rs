@@ -0,0 +1,122 @@
+// On-disk format for compiled programs
+
+use super::binary::{self, Decoder};
+use super::cell::CellWidth;
+use super::common::{Instruction, Program};
+use super::config::Config;
+use super::error::{Error, Result};
+use super::limits::Limits;
+
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 6] = b"BFCODE";
+const VERSION: u8 = 2;
+
+/// A compiled program and the settings it was compiled for
+///
+/// The file format is the magic bytes `BFCODE` and a version byte, then the
+/// cell width in bits, the tape size, tape policy and EOF policy as in a
+/// `Snapshot`, the instruction count as a LEB128 integer and the
+/// instructions, each an opcode byte followed by little-endian operands.
+/// Last comes a byte saying whether a source map follows; if it does, it holds
+/// a span per instruction, each as six LEB128 integers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bytecode {
+    pub program: Program,
+    /// Cell width to run with unless the user picks another
+    pub cell_width: CellWidth,
+    /// Tape and EOF settings to run with unless the user picks others.
+    /// Limits are not saved.
+    pub config: Config,
+}
+
+impl Bytecode {
+    /// Whether `bytes` start like a bytecode file rather than source
+    pub fn is_bytecode(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    /// Writes the program, with its source map if `source_map` is set and the
+    /// program has one
+    pub fn write_to<W: Write>(&self, out: &mut W, source_map: bool) -> io::Result<()> {
+        let instructions = &self.program.instructions;
+        let mut bytes = Vec::with_capacity(64 + instructions.len() * 4);
+        bytes.extend_from_slice(MAGIC);
+        binary::put_u8(&mut bytes, VERSION);
+        binary::put_u8(&mut bytes, self.cell_width.bits() as u8);
+        binary::put_config(&mut bytes, &self.config.clone().limits(Limits::default()));
+        binary::put_var(&mut bytes, instructions.len() as u64);
+        for &instr in instructions {
+            binary::put_instruction(&mut bytes, instr);
+        }
+        let spans = source_map && self.program.spans.len() == instructions.len();
+        binary::put_u8(&mut bytes, spans as u8);
+        if spans {
+            for &span in &self.program.spans {
+                binary::put_span(&mut bytes, span);
+            }
+        }
+        out.write_all(&bytes)
+    }
+
+    /// Reads a program written by `write_to`, checking that its jumps pair up
+    /// and that it ends in `Halt`, so it is safe to run
+    pub fn read_from<R: Read>(input: &mut R) -> Result<Self> {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes).map_err(|e| Error::Io(e, None))?;
+        let mut d = Decoder::new(&bytes, "bytecode");
+        if !Self::is_bytecode(&bytes) {
+            return Err(d.error("not a bytecode file"));
+        }
+        d.bytes(MAGIC.len())?;
+        if d.u8()? != VERSION {
+            return Err(d.error("unsupported version"));
+        }
+        let cell_width = match d.u8()? {
+            8 => CellWidth::U8,
+            16 => CellWidth::U16,
+            32 => CellWidth::U32,
+            64 => CellWidth::U64,
+            _ => return Err(d.error("unknown cell width")),
+        };
+        let config = d.config()?;
+        let len = d.var()?;
+        // Every instruction takes at least a byte, which bounds the allocation
+        if len > bytes.len() as u64 {
+            return Err(d.error("unexpected end of file"));
+        }
+        let instructions = (0..len).map(|_| d.instruction()).collect::<Result<Vec<_>>>()?;
+        let spans = match d.u8()? {
+            0 => Vec::new(),
+            1 => (0..len).map(|_| d.span()).collect::<Result<Vec<_>>>()?,
+            _ => return Err(d.error("corrupt source map flag")),
+        };
+        d.finish()?;
+        check(&instructions).map_err(|msg| d.error(msg))?;
+        Ok(Self {
+            program: Program { instructions, spans },
+            cell_width,
+            config,
+        })
+    }
+}
+
+// The VM trusts jumps to land on their partners and the program to end in
+// `Halt`, so a file must not be able to break either
+fn check(instructions: &[Instruction]) -> std::result::Result<(), &'static str> {
+    if instructions.last() != Some(&Instruction::Halt) {
+        return Err("program does not end in a halt");
+    }
+    for (idx, &instr) in instructions.iter().enumerate() {
+        let partner = match instr {
+            Instruction::Jez(d) => idx.checked_add(d as usize).map(|i| (i, Instruction::Jnz(d))),
+            Instruction::Jnz(d) => idx.checked_sub(d as usize).map(|i| (i, Instruction::Jez(d))),
+            _ => continue,
+        };
+        match partner {
+            Some((i, expected)) if instructions.get(i) == Some(&expected) => {}
+            _ => return Err("unmatched jump"),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+// Tape cell types
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Width of a tape cell, for choosing a `Cell` type at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CellWidth {
+    #[default]
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl CellWidth {
+    pub fn bits(self) -> u32 {
+        match self {
+            CellWidth::U8 => 8,
+            CellWidth::U16 => 16,
+            CellWidth::U32 => 32,
+            CellWidth::U64 => 64,
+        }
+    }
+}
+
+impl fmt::Display for CellWidth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.bits())
+    }
+}
+
+impl FromStr for CellWidth {
+    type Err = String;
+
+    /// Parses a width in bits: 8, 16, 32 or 64
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(CellWidth::U8),
+            "16" => Ok(CellWidth::U16),
+            "32" => Ok(CellWidth::U32),
+            "64" => Ok(CellWidth::U64),
+            _ => Err(format!("invalid cell width `{}`", s)),
+        }
+    }
+}
+
+/// A tape cell. All arithmetic wraps at the cell's width.
+pub trait Cell: Copy + Default + PartialEq + fmt::Debug + 'static {
+    const WIDTH: CellWidth;
+
+    /// `amount` truncated to the cell's width
+    fn from_i64(amount: i64) -> Self;
+    fn wrapping_add(self, rhs: Self) -> Self;
+    fn wrapping_mul(self, rhs: Self) -> Self;
+    /// Value stored by `,` when it reads byte `b`
+    fn from_byte(b: u8) -> Self;
+    /// Byte written by `.`, the low 8 bits of the cell
+    fn to_byte(self) -> u8;
+    fn to_u64(self) -> u64;
+
+    #[inline]
+    fn is_zero(self) -> bool {
+        self == Self::default()
+    }
+}
+
+macro_rules! impl_cell {
+    ($t:ty, $width:expr) => {
+        impl Cell for $t {
+            const WIDTH: CellWidth = $width;
+
+            #[inline]
+            fn from_i64(amount: i64) -> Self {
+                amount as $t
+            }
+
+            #[inline]
+            fn wrapping_add(self, rhs: Self) -> Self {
+                <$t>::wrapping_add(self, rhs)
+            }
+
+            #[inline]
+            fn wrapping_mul(self, rhs: Self) -> Self {
+                <$t>::wrapping_mul(self, rhs)
+            }
+
+            #[inline]
+            fn from_byte(b: u8) -> Self {
+                b as $t
+            }
+
+            #[inline]
+            fn to_byte(self) -> u8 {
+                self as u8
+            }
+
+            #[inline]
+            fn to_u64(self) -> u64 {
+                self as u64
+            }
+        }
+    };
+}
+
+impl_cell!(u8, CellWidth::U8);
+impl_cell!(u16, CellWidth::U16);
+impl_cell!(u32, CellWidth::U32);
+impl_cell!(u64, CellWidth::U64);
@@ -1,219 +1,413 @@
 // Takes Nodes and produces Instructions
+//
+// Code is generated in one walk over the nodes. Each instruction is optimized
+// as it is pushed, against the few before it, and each loop once its body is
+// done, so every instruction is looked at a bounded number of times and no
+// pass goes back over code already generated. Which optimizations apply is
+// up to an OptConfig; with none, the code follows the commands one for one
+// but for runs of moves and adds.
 
-use super::common::{Instruction, Node};
+use super::common::{Instruction, Node, NodeKind, Program, Span};
+use super::error::{Error, Result};
+use super::opt::{OptConfig, Pass};
 
-pub fn generate_code(nodes: Vec<Node>) -> Vec<Instruction> {
-    let code = generate_raw_code(nodes);
-    // Optimize at instruction level
-    let code = optimize_instruction_chain(&code);
-    code
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+/// Code for `nodes` with every optimization applied
+pub fn generate_code(nodes: Vec<Node>) -> Result<Program> {
+    generate_code_with(nodes, &OptConfig::default())
 }
 
-// Instruction opt
-fn optimize_instruction_chain(instrs: &[Instruction]) -> Vec<Instruction> {
-    let mut new_instructions = Vec::new();
-    new_instructions.extend(instrs);
-    new_instructions.reverse();
-    zero(&mut new_instructions);
-    dedup_zero(&mut new_instructions);
-    search(&mut new_instructions);
-    move_cell(&mut new_instructions);
-    zero_area(&mut new_instructions);
-    zero_area(&mut new_instructions);
-    mandel(&mut new_instructions);
-    new_instructions.reverse();
-    new_instructions
+/// Code for `nodes` with no optimizations, a reference for the optimized code
+pub fn generate_raw_code(nodes: Vec<Node>) -> Result<Program> {
+    generate_code_with(nodes, &OptConfig::none())
 }
 
-// [+] and [-] forms
-fn zero(instrs: &mut Vec<Instruction>) {
-    let mut idx = 0;
-    while idx + 3 <= instrs.len() {
-        match instrs[idx..idx+3] {
-            [Instruction::Jez(2), Instruction::Add(1), Instruction::Jnz(2)] => {
-                instrs.splice(idx..idx+3, [Instruction::Zero].iter().cloned());
-            },
-            [Instruction::Jez(2), Instruction::Add(255), Instruction::Jnz(2)] => {
-                instrs.splice(idx..idx+3, [Instruction::Zero].iter().cloned());
-            },
-            [Instruction::Jez(2), Instruction::Zero, Instruction::Jnz(2)] => {
-                instrs.splice(idx..idx+3, [Instruction::Zero].iter().cloned());
-            },
-            _ => {
-                idx += 1;
-            }
-        }
-    }
+/// Code for `nodes` with the optimizations `opt` enables
+pub fn generate_code_with(nodes: Vec<Node>, opt: &OptConfig) -> Result<Program> {
+    let mut code = Code::default();
+    block(&nodes, opt, &mut code)?;
+    Ok(Program {
+        instructions: code.instrs,
+        spans: code.spans,
+    })
 }
 
-// Zero Zero -> Zero
-fn dedup_zero(instrs: &mut Vec<Instruction>) {
-    let mut idx = 0;
-    while idx + 2 <= instrs.len() {
-        match instrs[idx..idx+2] {
-            [Instruction::Zero, Instruction::Zero] => {
-                instrs.splice(idx..idx+2, [Instruction::Zero].iter().cloned());
-            },
-            _ => {
-                idx += 1;
-            }
-        }
-    }
+// Instructions under construction, each with the source range it covers
+#[derive(Default)]
+struct Code {
+    instrs: Vec<Instruction>,
+    spans: Vec<Span>,
 }
 
-// [>] and [<] forms
-fn search(instrs: &mut Vec<Instruction>) {
-    let mut idx = 0;
-    while idx + 3 <= instrs.len() {
-        match instrs[idx..idx+3] {
-            [Instruction::Jez(2), Instruction::Left(n), Instruction::Jnz(2)] => {
-                instrs.splice(idx..idx+3, [Instruction::SearchLeft(n)].iter().cloned());
-            },
-            [Instruction::Jez(2), Instruction::Right(n), Instruction::Jnz(2)] => {
-                instrs.splice(idx..idx+3, [Instruction::SearchRight(n)].iter().cloned());
-            },
-            _ => {
-                idx += 1;
-            }
+impl Code {
+    fn push(&mut self, instr: Instruction, span: Span) {
+        self.instrs.push(instr);
+        self.spans.push(span);
+    }
+
+    fn len(&self) -> usize {
+        self.instrs.len()
+    }
+
+    fn last(&self) -> Option<Instruction> {
+        self.instrs.last().copied()
+    }
+
+    // Replaces the last instruction with one covering its source and `span`
+    fn merge(&mut self, instr: Instruction, span: Span) {
+        let last = self.len() - 1;
+        self.instrs[last] = instr;
+        self.spans[last] = self.spans[last].to(span);
+    }
+
+    fn last_spanned(&self) -> Option<(Instruction, Span)> {
+        self.instrs.last().copied().zip(self.spans.last().copied())
+    }
+
+    // Goes back to the first `len` instructions, the last of them as `last`
+    fn restore(&mut self, len: usize, last: Option<(Instruction, Span)>) {
+        self.instrs.truncate(len);
+        self.spans.truncate(len);
+        if let Some((instr, span)) = last {
+            self.instrs[len - 1] = instr;
+            self.spans[len - 1] = span;
         }
     }
 }
 
-// [-<<<<<<<<<<+>>>>>>>>>>] form
-fn move_cell(instrs: &mut Vec<Instruction>) {
+// One level of nesting
+fn block(nodes: &[Node], opt: &OptConfig, code: &mut Code) -> Result<()> {
+    let mut block = Block {
+        opt: *opt,
+        ..Default::default()
+    };
     let mut idx = 0;
-    while idx + 6 <= instrs.len() {
-        match instrs[idx..idx+6] {
-            [Instruction::Jez(_), Instruction::Add(n), Instruction::Right(r), Instruction::Add(n2), Instruction::Left(l), Instruction::Jnz(_)] => {
-                if r == l && n == 255 && n2 == 1 {
-                    // println!("{:?}", instrs);
-                    instrs.splice(idx..idx+6, [Instruction::AddMoveRight(r)].iter().cloned());
-                } else {
-                    idx += 1;
+    while let Some(node) = nodes.get(idx) {
+        idx += 1;
+        match &node.kind {
+            NodeKind::Loop(body) => {
+                // What comes before a loop is settled before it, then
+                // unsettled if the loop turns out to be replaced, as that
+                // may combine with it
+                let (saved, len, last) = (block, code.len(), code.last_spanned());
+                block.flush(code);
+                let start = code.len();
+                generate_loop(body, node.span, opt, code)?;
+                if let Some(instrs) = replacement(&code.instrs[start + 1..code.len() - 1], opt) {
+                    code.restore(len, last);
+                    block = saved;
+                    for instr in instrs {
+                        block.push(instr, node.span, code);
+                    }
                 }
-            },
-            [Instruction::Jez(_), Instruction::Add(n), Instruction::Left(l), Instruction::Add(n2), Instruction::Right(r), Instruction::Jnz(_)] => {
-                if r == l && n == 255 && n2 == 1 {
-                    // println!("{:?}", instrs);
-                    instrs.splice(idx..idx+6, [Instruction::AddMoveLeft(l)].iter().cloned());
+            }
+            NodeKind::MoveRight | NodeKind::MoveLeft => {
+                let (n, span, len) = net(&nodes[idx - 1..], NodeKind::MoveRight, NodeKind::MoveLeft);
+                let run = &nodes[idx - 1..idx - 1 + len];
+                idx += len - 1;
+                if run.iter().any(|node| node.kind != run[0].kind) {
+                    // A run that turns back is made one stretch at a time,
+                    // so that the cells it goes out to are checked and
+                    // leaving the tape is reported at the command that does
+                    block.flush_moves(code);
+                    for leg in run.chunk_by(|a, b| a.kind == b.kind) {
+                        let (n, span, _) = net(leg, NodeKind::MoveRight, NodeKind::MoveLeft);
+                        block.moved(n, span, code);
+                        block.flush_moves(code);
+                    }
                 } else {
-                    idx += 1;
+                    block.moved(n, span, code);
                 }
-            },
-            _ => {
-                idx += 1;
             }
+            NodeKind::Increment | NodeKind::Decrement => {
+                let (n, span, len) = net(&nodes[idx - 1..], NodeKind::Increment, NodeKind::Decrement);
+                idx += len - 1;
+                block.push(Instruction::Add(0, n), span, code);
+            }
+            NodeKind::Output => block.push(Instruction::Write(0), node.span, code),
+            NodeKind::Input => block.push(Instruction::Read, node.span, code),
+            NodeKind::Halt => block.push(Instruction::Halt, node.span, code),
         }
     }
+    block.flush(code);
+    Ok(())
 }
 
-// [-]>[-]>[-] and <[-]<[-]<[-] form
-fn zero_area(instrs: &mut Vec<Instruction>) {
-    let mut idx = 0;
-    while idx + 2 <= instrs.len() {
-        match instrs[idx..idx+2] {
-            [Instruction::Zero, Instruction::Right(1)] => {
-                instrs.splice(idx..idx+2, [Instruction::ZeroRight(1)].iter().cloned());
-            },
-            [Instruction::ZeroRight(n), Instruction::ZeroRight(n2)] => {
-                instrs.splice(idx..idx+2, [Instruction::ZeroRight(n+n2)].iter().cloned());
-            },
-            [Instruction::Zero, Instruction::Left(1)] => {
-                instrs.splice(idx..idx+2, [Instruction::ZeroLeft(1)].iter().cloned());
-            },
-            [Instruction::ZeroLeft(n), Instruction::ZeroLeft(n2)] => {
-                instrs.splice(idx..idx+2, [Instruction::ZeroLeft(n+n2)].iter().cloned());
-            },
-            _ => {
-                idx += 1;
-            }
+// Net effect of the run of `up` and `down` nodes `nodes` starts with, the
+// source it covers and its length. Amounts wrap at 64 bits, which is exact
+// for every narrower cell width too.
+fn net(nodes: &[Node], up: NodeKind, down: NodeKind) -> (i64, Span, usize) {
+    let (mut acc, mut span, mut len) = (0i64, nodes[0].span, 0);
+    for node in nodes {
+        if node.kind == up {
+            acc = acc.wrapping_add(1);
+        } else if node.kind == down {
+            acc = acc.wrapping_sub(1);
+        } else {
+            break;
         }
+        span = span.to(node.span);
+        len += 1;
     }
+    (acc, span, len)
 }
 
-// very common sequence in mandelbrot
-// while the current cell isn't 0:
-//   addmove the value of the cell x to the left to the cell x + n to the left, then change cell to current - n
-fn mandel(instrs: &mut Vec<Instruction>) {
-    let mut idx = 0;
-    while idx + 5 <= instrs.len() {
-        match instrs[idx..idx+5] {
-            [Instruction::Jez(4), Instruction::Right(x), Instruction::AddMoveRight(n),  Instruction::Left(q), Instruction::Jnz(4)] => {
-                if x + n == q {
-                    instrs.splice(idx..idx+5, [Instruction::Mandel(x, n)].iter().cloned());
-                } else {
-                    idx += 1;
+fn generate_loop(body: &[Node], span: Span, opt: &OptConfig, code: &mut Code) -> Result<()> {
+    let start = code.len();
+    // Only the brackets themselves are attributed to the jumps
+    code.push(Instruction::Jez(0), Span::byte(span.start));
+    block(body, opt, code)?;
+    let jump = u16::try_from(code.len() - start).map_err(|_| Error::LoopTooLong(Some(span.start)))?;
+    code.instrs[start] = Instruction::Jez(jump);
+    code.push(Instruction::Jnz(jump), closing_bracket(span));
+    Ok(())
+}
+
+// The `]` ending a loop, which is never preceded by a newline on its line
+fn closing_bracket(loop_span: Span) -> Span {
+    let mut start = loop_span.end;
+    start.offset -= 1;
+    start.column -= 1;
+    Span {
+        start,
+        end: loop_span.end,
+    }
+}
+
+// What a loop with this body, as generated, is replaced with, if anything
+fn replacement(body: &[Instruction], opt: &OptConfig) -> Option<Vec<Instruction>> {
+    match *body {
+        // [+], [-] and [[-]]
+        [Instruction::Add(0, 1)] | [Instruction::Add(0, -1)] | [Instruction::Zero] if opt.enabled(Pass::Zero) => {
+            Some(vec![Instruction::Zero])
+        }
+        // [>] and [<]
+        [Instruction::Left(n)] if opt.enabled(Pass::Search) => Some(vec![Instruction::SearchLeft(n)]),
+        [Instruction::Right(n)] if opt.enabled(Pass::Search) => Some(vec![Instruction::SearchRight(n)]),
+        // very common sequence in mandelbrot
+        // while the current cell isn't 0:
+        //   addmove the value of the cell x to the right to the cell x + n to the right, then move n to the left
+        [Instruction::Right(x), Instruction::AddMoveRight(n), Instruction::Left(q)]
+            if opt.enabled(Pass::Mandel) && x.checked_add(n) == Some(q) =>
+        {
+            Some(vec![Instruction::Mandel(x, n)])
+        }
+        _ if opt.enabled(Pass::Linear) => linear_loop(body),
+        _ => None,
+    }
+}
+
+// Loops that only add and move, end where they start, and take one from the
+// cell they test or add one to it, like [->+>+++<<]: each turn adds a
+// multiple of that cell to others, so the loop becomes a MulAdd per target
+// and a Zero. A single target taking the cell as is becomes an AddMove.
+fn linear_loop(body: &[Instruction]) -> Option<Vec<Instruction>> {
+    // Net amount added at each offset, in the order first reached
+    let mut adds: Vec<(i64, i64)> = Vec::new();
+    let mut seen: HashMap<i64, usize> = HashMap::new();
+    let (mut offset, mut lo, mut hi) = (0i64, 0i64, 0i64);
+    for &instr in body {
+        match instr {
+            Instruction::Add(o, n) => {
+                let at = offset + o as i64;
+                match seen.get(&at) {
+                    Some(&i) => adds[i].1 = adds[i].1.wrapping_add(n),
+                    None => {
+                        seen.insert(at, adds.len());
+                        adds.push((at, n));
+                    }
                 }
-            },
-            _ => {
-                idx += 1;
+                lo = lo.min(at);
+                hi = hi.max(at);
             }
+            Instruction::Right(n) => offset += n as i64,
+            Instruction::Left(n) => offset -= n as i64,
+            _ => return None,
         }
+        lo = lo.min(offset);
+        hi = hi.max(offset);
     }
+    if offset != 0 {
+        return None;
+    }
+    // Counting up instead of down runs the loop minus the cell times
+    let negate = match seen.get(&0).map(|&i| adds[i].1) {
+        Some(-1) => false,
+        Some(1) => true,
+        _ => return None,
+    };
+    let targets: Vec<(i64, i64)> = adds
+        .into_iter()
+        .filter(|&(o, amount)| o != 0 && amount != 0)
+        .map(|(o, amount)| (o, if negate { amount.wrapping_neg() } else { amount }))
+        .collect();
+    // The loop must not wander past its outermost targets, or the tape
+    // errors and limits it would hit could be missed
+    let reaches = |end: i64| end == 0 || targets.iter().any(|&(o, _)| o == end);
+    if !reaches(lo) || !reaches(hi) {
+        return None;
+    }
+    match targets[..] {
+        [(o, 1)] if o > 0 => return u16::try_from(o).ok().map(|n| vec![Instruction::AddMoveRight(n)]),
+        [(o, 1)] => return u16::try_from(-o).ok().map(|n| vec![Instruction::AddMoveLeft(n)]),
+        _ => {}
+    }
+    let mut instrs = targets
+        .iter()
+        .map(|&(o, factor)| i32::try_from(o).ok().map(|o| Instruction::MulAdd(o, factor)))
+        .collect::<Option<Vec<_>>>()?;
+    instrs.push(Instruction::Zero);
+    Some(instrs)
 }
 
+// State of the level being generated.
+//
+// Moves between the instructions that take an offset are folded into those
+// offsets, so >+>>-<. becomes add 1 1, add 3 -1, write 2, right 2: the
+// pointer only moves once, before anything that can't take an offset and at
+// the end. Adds and sets to the cell just set or added to are merged as well,
+// and so are [-]>[-]>[-] and <[-]<[-]<[-] into a ZeroRight or ZeroLeft.
+// Without those passes each instruction goes in as it comes.
+#[derive(Default, Clone, Copy)]
+struct Block {
+    opt: OptConfig,
+    // How far the pointer has moved since it last did in the code
+    shift: i64,
+    // The moves making up `shift`, if any
+    moves: Option<Span>,
+    // Those of them since the last instruction taking an offset
+    recent: Option<Span>,
+    // A Zero held back in case a move of one cell comes next
+    zero: Option<Span>,
+}
 
-// Basic codegen
+impl Block {
+    fn moved(&mut self, n: i64, span: Span, code: &mut Code) {
+        if let Some(zero) = self.zero.take() {
+            match n {
+                1 => return self.barrier(Instruction::ZeroRight(1), zero.to(span), code),
+                -1 => return self.barrier(Instruction::ZeroLeft(1), zero.to(span), code),
+                _ => self.set(0, 0, zero, code),
+            }
+        }
+        self.shift += n;
+        self.moves = Some(self.moves.map_or(span, |s| s.to(span)));
+        self.recent = Some(self.recent.map_or(span, |s| s.to(span)));
+        if !self.opt.enabled(Pass::Offsets) {
+            self.flush_moves(code);
+        }
+    }
 
-fn generate_raw_code(mut nodes: Vec<Node>) -> Vec<Instruction> {
-    let mut instructions = Vec::new();
-    while let Some(node) = nodes.pop() {
-        match node {
-            Node::Loop(inner_nodes) => {
-                let inner_raw_instructions = generate_raw_code(inner_nodes);
-                let inner_instructions = optimize_instruction_chain(&inner_raw_instructions);
-                let inner_size = inner_instructions.len();
-                instructions.push(Instruction::Jnz(inner_size as u16 + 1));
-                instructions.extend(&inner_instructions);
-                instructions.push(Instruction::Jez(inner_size as u16 + 1));
+    fn push(&mut self, instr: Instruction, span: Span, code: &mut Code) {
+        if let Instruction::Zero = instr {
+            if self.opt.enabled(Pass::ZeroRuns) {
+                self.zero = Some(self.zero.map_or(span, |s| s.to(span)));
+                return;
             }
-            Node::MoveRight => instructions.push(optimize_ptr(1, &mut nodes)),
-            Node::MoveLeft => instructions.push(optimize_ptr(-1, &mut nodes)),
-            Node::Increment => instructions.push(Instruction::Add(optimize_math(1, &mut nodes))),
-            Node::Decrement => instructions.push(Instruction::Add(optimize_math(255, &mut nodes))),
-            Node::Output => instructions.push(Instruction::Write),
-            Node::Input => instructions.push(Instruction::Read),
-            Node::Halt => instructions.push(Instruction::Halt),
+        }
+        if let Some(zero) = self.zero.take() {
+            self.set(0, 0, zero, code);
+        }
+        match instr {
+            Instruction::Zero => self.set(0, 0, span, code),
+            Instruction::Add(o, n) => self.add(o, n, span, code),
+            Instruction::Set(o, v) => self.set(o, v, span, code),
+            Instruction::Write(o) => {
+                let (o, span) = self.offset(o, span, code);
+                code.push(Instruction::Write(o), span);
+            }
+            _ => self.barrier(instr, span, code),
         }
     }
-    instructions
-}
 
-fn optimize_math(start: u8, nodes: &mut Vec<Node>) -> u8 {
-    let mut acc = start;
-    loop {
-        match nodes.last() {
-            Some(Node::Increment) => {
-                acc = acc.wrapping_add(1);
-                nodes.pop();
+    // An instruction that needs the pointer where the commands would have it
+    fn barrier(&mut self, instr: Instruction, span: Span, code: &mut Code) {
+        self.flush_moves(code);
+        match (code.last(), instr) {
+            (Some(Instruction::ZeroRight(n)), Instruction::ZeroRight(m)) if n.checked_add(m).is_some() => {
+                code.merge(Instruction::ZeroRight(n + m), span)
             }
-            Some(Node::Decrement) => {
-                acc = acc.wrapping_sub(1);
-                nodes.pop();
+            (Some(Instruction::ZeroLeft(n)), Instruction::ZeroLeft(m)) if n.checked_add(m).is_some() => {
+                code.merge(Instruction::ZeroLeft(n + m), span)
             }
-            _ => return acc,
+            _ => code.push(instr, span),
         }
     }
-}
 
-fn optimize_ptr(start: i16, nodes: &mut Vec<Node>) -> Instruction {
-    let mut acc = start;
-    loop {
-        match nodes.last() {
-            Some(Node::MoveRight) => {
-                acc += 1;
-                nodes.pop();
+    // `offset` from where the pointer would be, as an offset from where it is,
+    // and `span` taking in the moves since the last such instruction, so that
+    // leaving the tape is reported where the commands would have left it
+    fn offset(&mut self, offset: i32, span: Span, code: &mut Code) -> (i32, Span) {
+        let o = match i32::try_from(self.shift + offset as i64) {
+            Ok(o) => o,
+            Err(_) => {
+                self.flush_moves(code);
+                offset
             }
-            Some(Node::MoveLeft) => {
-                acc -= 1;
-                nodes.pop();
+        };
+        match self.recent {
+            Some(moves) if o != 0 => {
+                self.recent = None;
+                (o, moves.to(span))
             }
-            _ => {
-                if acc > 0 {
-                    return Instruction::Right(acc as u16);
-                } else {
-                    return Instruction::Left(acc.abs() as u16);
-                }
+            _ => (o, span),
+        }
+    }
+
+    fn add(&mut self, offset: i32, n: i64, span: Span, code: &mut Code) {
+        let (o, span) = self.offset(offset, span, code);
+        if !self.opt.enabled(Pass::Offsets) {
+            return code.push(Instruction::Add(o, n), span);
+        }
+        let merged = match code.last() {
+            Some(Instruction::Add(p, m)) if p == o => Instruction::Add(o, m.wrapping_add(n)),
+            Some(Instruction::Set(p, v)) if p == o => Instruction::Set(o, v.wrapping_add(n)),
+            Some(Instruction::Zero) if o == 0 => Instruction::Set(0, n),
+            _ => return code.push(Instruction::Add(o, n), span),
+        };
+        code.merge(merged, span);
+    }
+
+    fn set(&mut self, offset: i32, v: i64, span: Span, code: &mut Code) {
+        let (o, span) = self.offset(offset, span, code);
+        let set = match (o, v) {
+            (0, 0) => Instruction::Zero,
+            _ => Instruction::Set(o, v),
+        };
+        if !self.opt.enabled(Pass::Offsets) {
+            return code.push(set, span);
+        }
+        match code.last() {
+            Some(Instruction::Add(p, _)) | Some(Instruction::Set(p, _)) if p == o => code.merge(set, span),
+            Some(Instruction::Zero) if o == 0 => code.merge(set, span),
+            _ => code.push(set, span),
+        }
+    }
+
+    // Makes everything held back, for the end of the level or a loop
+    fn flush(&mut self, code: &mut Code) {
+        if let Some(zero) = self.zero.take() {
+            self.set(0, 0, zero, code);
+        }
+        self.flush_moves(code);
+    }
+
+    // Moves the pointer by `shift`, in as few instructions as fit a u16
+    fn flush_moves(&mut self, code: &mut Code) {
+        let span = match (self.recent.take(), self.moves.take()) {
+            (Some(span), _) | (None, Some(span)) => span,
+            (None, None) => return,
+        };
+        while self.shift != 0 {
+            let n = self.shift.unsigned_abs().min(u16::MAX as u64) as u16;
+            if self.shift > 0 {
+                code.push(Instruction::Right(n), span);
+                self.shift -= n as i64;
+            } else {
+                code.push(Instruction::Left(n), span);
+                self.shift += n as i64;
             }
         }
     }
@@ -1,5 +1,67 @@
+use super::binary;
+
+use std::fmt;
+use std::io;
+
+/// Location of a byte in the program source. Lines and columns start at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Self {
+            offset: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// Range of source bytes, `end` being the position just past the last one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Span {
+    /// Span of the single command byte at `pos`
+    pub fn byte(pos: Position) -> Self {
+        let end = Position {
+            offset: pos.offset + 1,
+            line: pos.line,
+            column: pos.column + 1,
+        };
+        Self { start: pos, end }
+    }
+
+    /// Smallest span covering both `self` and `other`
+    pub fn to(self, other: Span) -> Self {
+        Self {
+            start: if other.start.offset < self.start.offset { other.start } else { self.start },
+            end: if other.end.offset > self.end.offset { other.end } else { self.end },
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
 /// Tokens straight from the input stream
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Token {
     MoveRight,
     MoveLeft,
@@ -9,12 +71,72 @@ pub enum Token {
     LeftLoop,
     Output,
     Input,
-    EOF,
+    Eof,
+}
+
+impl Token {
+    /// The command character the token stands for, None for `Eof`
+    pub fn byte(self) -> Option<u8> {
+        match self {
+            Token::MoveRight => Some(b'>'),
+            Token::MoveLeft => Some(b'<'),
+            Token::Increment => Some(b'+'),
+            Token::Decrement => Some(b'-'),
+            Token::RightLoop => Some(b']'),
+            Token::LeftLoop => Some(b'['),
+            Token::Output => Some(b'.'),
+            Token::Input => Some(b','),
+            Token::Eof => None,
+        }
+    }
+
+    /// The token for a command character, None for any other byte
+    pub fn from_byte(b: u8) -> Option<Token> {
+        match b {
+            b'>' => Some(Token::MoveRight),
+            b'<' => Some(Token::MoveLeft),
+            b'+' => Some(Token::Increment),
+            b'-' => Some(Token::Decrement),
+            b']' => Some(Token::RightLoop),
+            b'[' => Some(Token::LeftLoop),
+            b'.' => Some(Token::Output),
+            b',' => Some(Token::Input),
+            _ => None,
+        }
+    }
+}
+
+/// A token with the comments and whitespace, its trivia, on either side of it.
+/// Writing out each lexeme of a source in turn gives the source back byte for
+/// byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lexeme {
+    pub token: Token,
+    pub pos: Position,
+    pub leading: Vec<u8>,
+    pub trailing: Vec<u8>,
+}
+
+impl Lexeme {
+    /// The lexeme's bytes: its leading trivia, command and trailing trivia
+    pub fn write_to<W: io::Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(&self.leading)?;
+        if let Some(b) = self.token.byte() {
+            out.write_all(&[b])?;
+        }
+        out.write_all(&self.trailing)
+    }
 }
 
 /// Intermediate representation
 #[derive(Debug, PartialEq, Eq)]
-pub enum Node {
+pub struct Node {
+    pub kind: NodeKind,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NodeKind {
     Loop(Vec<Node>),
     MoveRight,
     MoveLeft,
@@ -25,10 +147,11 @@ pub enum Node {
     Halt,
 }
 
-/// Instructions to be executed by the VM
+/// Instructions to be executed by the VM. Offsets are in cells from the data
+/// pointer, which only the moving instructions change.
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub enum Instruction {
-    Add(u8),  // Subtraction isn't required
+    Add(i32, i64), // Offset and amount, which wraps at the cell width
     Jez(u16), // Jumps forward
     Jnz(u16), // Jumps backward
     Left(u16),
@@ -40,8 +163,63 @@ pub enum Instruction {
     ZeroRight(u16),
     ZeroLeft(u16),
     Mandel(u16, u16),
+    MulAdd(i32, i64), // Adds the current cell times the factor to the cell at the offset
+    Set(i32, i64), // Offset and value
     Zero, // Zero current cell
-    Write,
+    Write(i32), // Writes the cell at the offset
     Read,
     Halt,
 }
+
+impl Instruction {
+    /// Lowercase name of the instruction, without its operands
+    pub fn name(&self) -> &'static str {
+        match self {
+            Instruction::Add(..) => "add",
+            Instruction::Jez(_) => "jez",
+            Instruction::Jnz(_) => "jnz",
+            Instruction::Left(_) => "left",
+            Instruction::Right(_) => "right",
+            Instruction::SearchLeft(_) => "search_left",
+            Instruction::SearchRight(_) => "search_right",
+            Instruction::AddMoveRight(_) => "add_move_right",
+            Instruction::AddMoveLeft(_) => "add_move_left",
+            Instruction::ZeroRight(_) => "zero_right",
+            Instruction::ZeroLeft(_) => "zero_left",
+            Instruction::Mandel(..) => "mandel",
+            Instruction::MulAdd(..) => "mul_add",
+            Instruction::Set(..) => "set",
+            Instruction::Zero => "zero",
+            Instruction::Write(_) => "write",
+            Instruction::Read => "read",
+            Instruction::Halt => "halt",
+        }
+    }
+}
+
+/// Generated code, with the source range each instruction was built from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    /// Parallel to `instructions`; empty when no source is known
+    pub spans: Vec<Span>,
+}
+
+impl Program {
+    /// Source range instruction `idx` was built from, if known
+    pub fn span(&self, idx: usize) -> Option<Span> {
+        self.spans.get(idx).copied()
+    }
+
+    /// Hash of the instructions, to check that saved state belongs to this program
+    pub fn fingerprint(&self) -> u64 {
+        binary::fingerprint(&self.instructions)
+    }
+
+    /// First instruction built from source at or after `line` and `column`
+    pub fn instruction_at(&self, line: usize, column: usize) -> Option<usize> {
+        self.spans
+            .iter()
+            .position(|s| (s.end.line, s.end.column) > (line, column))
+    }
+}
@@ -0,0 +1,133 @@
+// Runtime settings shared by both interpreters
+
+use super::cell::Cell;
+use super::limits::Limits;
+
+use std::str::FromStr;
+
+/// What happens when the data pointer moves past either end of the tape
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum TapePolicy {
+    /// Continue from the other end
+    #[default]
+    Wrap,
+    /// Stop with `Error::TapeOutOfBounds`
+    Error,
+    /// Extend the tape on the right; moving left of the first cell is an error
+    GrowRight,
+    /// Extend the tape on whichever side the pointer leaves
+    Grow,
+}
+
+impl FromStr for TapePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrap" => Ok(TapePolicy::Wrap),
+            "error" => Ok(TapePolicy::Error),
+            "grow-right" => Ok(TapePolicy::GrowRight),
+            "grow" => Ok(TapePolicy::Grow),
+            _ => Err(format!("invalid tape policy `{}`", s)),
+        }
+    }
+}
+
+/// What `,` stores once the input is exhausted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum EofPolicy {
+    /// Leave the cell as it was
+    #[default]
+    Unchanged,
+    /// Store 0
+    Zero,
+    /// Store -1, the cell's maximum value
+    Max,
+}
+
+impl EofPolicy {
+    /// New value for a cell holding `cell` when `,` hits EOF
+    #[inline]
+    pub fn apply<C: Cell>(self, cell: C) -> C {
+        match self {
+            EofPolicy::Unchanged => cell,
+            EofPolicy::Zero => C::default(),
+            EofPolicy::Max => C::from_i64(-1),
+        }
+    }
+}
+
+impl FromStr for EofPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unchanged" => Ok(EofPolicy::Unchanged),
+            "zero" | "0" => Ok(EofPolicy::Zero),
+            "max" | "-1" => Ok(EofPolicy::Max),
+            _ => Err(format!("invalid EOF policy `{}`", s)),
+        }
+    }
+}
+
+/// Interpreter settings. The cell width is chosen by the interpreter's `Cell` type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// Number of cells on the tape, or its starting size if it can grow
+    pub tape_size: usize,
+    pub tape_policy: TapePolicy,
+    pub eof_policy: EofPolicy,
+    pub limits: Limits,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            tape_size: 30000,
+            tape_policy: Default::default(),
+            eof_policy: Default::default(),
+            limits: Default::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn tape_size(mut self, cells: usize) -> Self {
+        self.tape_size = cells;
+        self
+    }
+
+    pub fn tape_policy(mut self, policy: TapePolicy) -> Self {
+        self.tape_policy = policy;
+        self
+    }
+
+    pub fn eof_policy(mut self, policy: EofPolicy) -> Self {
+        self.eof_policy = policy;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn max_steps(mut self, steps: u64) -> Self {
+        self.limits.steps = Some(steps);
+        self
+    }
+
+    pub fn max_output(mut self, bytes: u64) -> Self {
+        self.limits.output = Some(bytes);
+        self
+    }
+
+    pub fn max_cells(mut self, cells: usize) -> Self {
+        self.limits.cells = Some(cells);
+        self
+    }
+}
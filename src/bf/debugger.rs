@@ -0,0 +1,197 @@
+// Breakpoints, watchpoints and stepping on top of the optimizing VM
+
+use super::cell::Cell;
+use super::common::{Instruction, Program};
+use super::error::Error;
+use super::vm::{Interpreter, Status};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Write};
+
+/// Why the debugger handed control back
+#[derive(Debug)]
+pub enum Stop<C: Cell> {
+    /// The requested steps are done
+    Step,
+    /// The pc reached the breakpoint on this instruction
+    Breakpoint(usize),
+    /// The last instruction changed a watched cell
+    Watchpoint { cell: usize, old: C, new: C },
+    /// A `,` is waiting for input that is not ready yet
+    NeedsInput,
+    Halted,
+    Error(Error),
+}
+
+pub struct Debugger<'a, C: Cell, T: Read, U: Write> {
+    vm: Interpreter<'a, C, T, U>,
+    program: &'a Program,
+    breakpoints: BTreeSet<usize>,
+    // Watched cells and the value each had when last checked
+    watchpoints: BTreeMap<usize, C>,
+    // The pc when control last came back to the caller. A breakpoint there has
+    // been seen, so running on passes over it.
+    stopped_at: Option<usize>,
+    finished: bool,
+}
+
+impl<'a, C: Cell, T: Read, U: Write> Debugger<'a, C, T, U> {
+    /// Debugs `vm`, which must be running `program` and not have started yet
+    pub fn new(vm: Interpreter<'a, C, T, U>, program: &'a Program) -> Self {
+        Self {
+            vm,
+            program,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            stopped_at: None,
+            finished: false,
+        }
+    }
+
+    pub fn interpreter(&self) -> &Interpreter<'a, C, T, U> {
+        &self.vm
+    }
+
+    pub fn program(&self) -> &'a Program {
+        self.program
+    }
+
+    /// Whether the program has halted or failed
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Sets a breakpoint on the first instruction at or after `line` and
+    /// `column`, returning the instruction's index
+    pub fn break_at(&mut self, line: usize, column: usize) -> Option<usize> {
+        let idx = self.program.instruction_at(line, column)?;
+        self.breakpoints.insert(idx);
+        Some(idx)
+    }
+
+    /// Sets a breakpoint for each `#` in `source`, the program's source, on
+    /// the first instruction at or after it. A `#` inside a run of commands the
+    /// optimizer merged breaks before the whole run.
+    pub fn break_on_hashes(&mut self, source: &[u8]) -> Vec<usize> {
+        let (mut line, mut column) = (1, 1);
+        let mut set = Vec::new();
+        for &b in source {
+            if b == b'#' {
+                set.extend(self.break_at(line, column));
+            }
+            if b == b'\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        set
+    }
+
+    /// Removes the breakpoint on instruction `idx`, returning whether there was one
+    pub fn remove_breakpoint(&mut self, idx: usize) -> bool {
+        self.breakpoints.remove(&idx)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Stops whenever an instruction changes `cell`. Returns false if the cell
+    /// is not on the tape.
+    pub fn watch(&mut self, cell: usize) -> bool {
+        match self.vm.tape().get(cell) {
+            Some(&v) => {
+                self.watchpoints.insert(cell, v);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn unwatch(&mut self, cell: usize) -> bool {
+        self.watchpoints.remove(&cell).is_some()
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.watchpoints.keys().copied()
+    }
+
+    /// Runs one instruction. Fused instructions, like a whole `[-]`, count as one.
+    pub fn step(&mut self) -> Stop<C> {
+        let stop = self.single().unwrap_or(Stop::Step);
+        self.stopped(stop)
+    }
+
+    /// Runs the loop starting at the pc to completion, or steps once when the
+    /// pc is not at the start of a loop
+    pub fn step_over(&mut self) -> Stop<C> {
+        let pc = self.vm.pc();
+        let end = match self.program.instructions.get(pc) {
+            Some(Instruction::Jez(d)) => pc + *d as usize + 1,
+            _ => return self.step(),
+        };
+        let stop = self.run_to(Some(end));
+        self.stopped(stop)
+    }
+
+    /// Runs until a breakpoint, a watchpoint or the end of the program. A
+    /// breakpoint on the pc counts unless the last stop was already there.
+    pub fn resume(&mut self) -> Stop<C> {
+        let stop = self.run_to(None);
+        self.stopped(stop)
+    }
+
+    // Runs until the pc reaches `end`, a breakpoint or another reason to stop
+    fn run_to(&mut self, end: Option<usize>) -> Stop<C> {
+        let mut passed = self.stopped_at;
+        loop {
+            let pc = self.vm.pc();
+            if passed.take() != Some(pc) && self.breakpoints.contains(&pc) {
+                return Stop::Breakpoint(pc);
+            }
+            if let Some(stop) = self.single() {
+                return stop;
+            }
+            if Some(self.vm.pc()) == end {
+                return Stop::Step;
+            }
+        }
+    }
+
+    fn stopped(&mut self, stop: Stop<C>) -> Stop<C> {
+        self.stopped_at = Some(self.vm.pc());
+        stop
+    }
+
+    // Runs one instruction, returning why to stop if there is a reason other
+    // than breakpoints
+    fn single(&mut self) -> Option<Stop<C>> {
+        if self.finished {
+            return Some(Stop::Halted);
+        }
+        match self.vm.step(1) {
+            Status::Paused => {}
+            Status::NeedsInput => return Some(Stop::NeedsInput),
+            Status::Halted => {
+                self.finished = true;
+                return Some(Stop::Halted);
+            }
+            Status::Error(e) => {
+                self.finished = true;
+                return Some(Stop::Error(e));
+            }
+        }
+        let tape = self.vm.tape();
+        for (&cell, old) in self.watchpoints.iter_mut() {
+            let new = tape.get(cell).copied().unwrap_or_default();
+            if new != *old {
+                let stop = Stop::Watchpoint { cell, old: *old, new };
+                *old = new;
+                return Some(stop);
+            }
+        }
+        None
+    }
+}
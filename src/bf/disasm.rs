@@ -0,0 +1,74 @@
+// Readable listing of generated code
+
+use super::common::{Instruction, Program, Span};
+use Instruction::*;
+
+use std::fmt;
+use std::io::{self, Write};
+
+// Widest the source column gets before it is cut short
+const SNIPPET_WIDTH: usize = 40;
+
+/// An instruction as written in listings: its name, then its operands. Jumps
+/// show the index they land on instead of their distance.
+pub struct Mnemonic {
+    pub index: usize,
+    pub instruction: Instruction,
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = self.instruction.name();
+        match self.instruction {
+            Add(offset, n) | Set(offset, n) | MulAdd(offset, n) => write!(f, "{} {} {}", name, offset, n),
+            Jez(d) => write!(f, "{} -> {}", name, self.index + d as usize + 1),
+            Jnz(d) => write!(f, "{} -> {}", name, (self.index + 1).wrapping_sub(d as usize)),
+            Left(n) | Right(n) | SearchLeft(n) | SearchRight(n) | AddMoveRight(n) | AddMoveLeft(n) | ZeroRight(n)
+            | ZeroLeft(n) => write!(f, "{} {}", name, n),
+            Mandel(x, n) => write!(f, "{} {} {}", name, x, n),
+            Write(offset) => write!(f, "{} {}", name, offset),
+            Zero | Read | Halt => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Lists `program` one instruction a line: its index, the instruction indented
+/// by loop depth, and where it came from. `source`, the program's source, adds
+/// the text of each span, whitespace collapsed and cut short if long.
+pub fn disassemble<W: Write>(program: &Program, source: Option<&[u8]>, out: &mut W) -> io::Result<()> {
+    let digits = program.instructions.len().saturating_sub(1).to_string().len();
+    let mut depth = 0usize;
+    for (index, &instruction) in program.instructions.iter().enumerate() {
+        if let Jnz(_) = instruction {
+            depth = depth.saturating_sub(1);
+        }
+        let text = format!("{:indent$}{}", "", Mnemonic { index, instruction }, indent = depth * 2);
+        match program.span(index) {
+            Some(span) => {
+                let line = format!("{:>digits$}  {:<28} {:<12}", index, text, span.to_string(), digits = digits);
+                match source.map(|s| snippet(s, span)) {
+                    Some(snippet) => writeln!(out, "{} {}", line, snippet)?,
+                    None => writeln!(out, "{}", line.trim_end())?,
+                }
+            }
+            None => writeln!(out, "{:>digits$}  {}", index, text, digits = digits)?,
+        }
+        if let Jez(_) = instruction {
+            depth += 1;
+        }
+    }
+    Ok(())
+}
+
+fn snippet(source: &[u8], span: Span) -> String {
+    let end = span.end.offset.min(source.len());
+    let start = span.start.offset.min(end);
+    let text = String::from_utf8_lossy(&source[start..end]);
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() > SNIPPET_WIDTH {
+        let cut: String = text.chars().take(SNIPPET_WIDTH - 3).collect();
+        format!("{}...", cut)
+    } else {
+        text
+    }
+}
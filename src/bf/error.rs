@@ -0,0 +1,93 @@
+// Errors produced anywhere between reading source and running it
+
+use super::common::Position;
+use super::limits::Limit;
+
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A `[` that is never closed
+    UnmatchedOpen(Position),
+    /// A `]` with no `[` before it
+    UnmatchedClose(Position),
+    /// A loop body too large to encode in a jump instruction
+    LoopTooLong(Option<Position>),
+    /// The data pointer moved past either end of the tape
+    TapeOutOfBounds(Option<Position>),
+    /// The run reached one of the configured `Limits`
+    LimitExceeded(Limit, Option<Position>),
+    /// A snapshot or bytecode file is malformed, or does not fit what it is
+    /// being loaded into
+    Format(String),
+    /// The configuration asks for something the engine cannot do
+    Unsupported(String),
+    /// Reading the source or program input, or writing output, failed
+    Io(io::Error, Option<Position>),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Source position of the byte that caused the error, if known
+    pub fn position(&self) -> Option<Position> {
+        match *self {
+            Error::UnmatchedOpen(pos) | Error::UnmatchedClose(pos) => Some(pos),
+            Error::LoopTooLong(pos)
+            | Error::TapeOutOfBounds(pos)
+            | Error::LimitExceeded(_, pos)
+            | Error::Io(_, pos) => pos,
+            Error::Format(_) | Error::Unsupported(_) => None,
+        }
+    }
+
+    // Attaches `pos` to an error raised without a position
+    pub(crate) fn or_at(self, pos: Option<Position>) -> Self {
+        match self {
+            Error::LoopTooLong(None) => Error::LoopTooLong(pos),
+            Error::TapeOutOfBounds(None) => Error::TapeOutOfBounds(pos),
+            Error::LimitExceeded(limit, None) => Error::LimitExceeded(limit, pos),
+            Error::Io(e, None) => Error::Io(e, pos),
+            e => e,
+        }
+    }
+
+    /// Whether the program was rejected before it started running
+    pub fn is_compile_error(&self) -> bool {
+        match self {
+            Error::UnmatchedOpen(_) | Error::UnmatchedClose(_) | Error::LoopTooLong(_) => true,
+            Error::TapeOutOfBounds(_)
+            | Error::LimitExceeded(..)
+            | Error::Format(_)
+            | Error::Unsupported(_)
+            | Error::Io(..) => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(pos) = self.position() {
+            write!(f, "{}: ", pos)?;
+        }
+        match self {
+            Error::UnmatchedOpen(_) => write!(f, "unmatched `[`"),
+            Error::UnmatchedClose(_) => write!(f, "unmatched `]`"),
+            Error::LoopTooLong(_) => write!(f, "loop body too long"),
+            Error::TapeOutOfBounds(_) => write!(f, "tape pointer out of bounds"),
+            Error::LimitExceeded(limit, _) => write!(f, "{} limit exceeded", limit),
+            Error::Format(msg) | Error::Unsupported(msg) => write!(f, "{}", msg),
+            Error::Io(e, _) => write!(f, "i/o error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e, _) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,231 @@
+// Pretty-printer for Brainfuck source
+
+use super::common::{Node, NodeKind, Token};
+use super::error::Result;
+use super::lexer::Lexer;
+use super::parser::Parser;
+
+/// How `format` lays out a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    /// Spaces per level of loop nesting
+    pub indent: usize,
+    /// Widest a line of commands gets; comments may run past it
+    pub width: usize,
+    /// Keep the text between commands as comments instead of dropping it
+    pub comments: bool,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self {
+            indent: 4,
+            width: 80,
+            comments: false,
+        }
+    }
+}
+
+/// Reformats `source`. Runs of `+` and `-`, `<` and `>`, `.` and `,` are each
+/// written together and set apart by spaces, filling lines up to
+/// `layout.width`. A loop with no loop inside it that fits stays on the line,
+/// as in `[->+<]`; any other opens with `[` on a line of its own, its body
+/// indented a level, and closes with `]` on another.
+///
+/// With `layout.comments` each comment is kept where it was: at the end of the
+/// line if it followed a command on the same source line, otherwise on a line
+/// of its own. A blank line between comments or commands stays as one. The
+/// commands, and so what the program does, never change, and formatting the
+/// result again gives it back unchanged.
+pub fn format(source: &[u8], layout: &Layout) -> Result<String> {
+    let nodes = Parser::new(Lexer::new(source)).collect::<Result<Vec<_>>>()?;
+    let mut printer = Printer {
+        source,
+        layout,
+        out: String::new(),
+        line: String::new(),
+        run: String::new(),
+        depth: 0,
+        closed: false,
+        blank: false,
+    };
+    printer.nodes(&nodes, 0, source.len());
+    printer.end_line();
+    Ok(printer.out)
+}
+
+struct Printer<'a> {
+    source: &'a [u8],
+    layout: &'a Layout,
+    out: String,
+    // The line being filled, indentation included; empty until it gets text
+    line: String,
+    // Commands of one kind not yet written
+    run: String,
+    depth: usize,
+    // The line ends in `[`, `]` or a comment, so takes no more commands
+    closed: bool,
+    // A blank line goes before the next line written
+    blank: bool,
+}
+
+impl<'a> Printer<'a> {
+    // `nodes` and the text around them, which runs from byte `from` to `to`
+    fn nodes(&mut self, nodes: &[Node], from: usize, to: usize) {
+        let mut at = from;
+        for node in nodes {
+            self.gap(at, node.span.start.offset);
+            at = node.span.end.offset;
+            let command = match &node.kind {
+                NodeKind::Loop(body) => {
+                    self.flush_run();
+                    self.looped(body, node.span.start.offset + 1, node.span.end.offset - 1);
+                    continue;
+                }
+                kind => match command(kind) {
+                    Some(c) => c,
+                    None => continue,
+                },
+            };
+            if self.run.chars().next().is_some_and(|c| group(c) != group(command)) {
+                self.flush_run();
+            }
+            self.run.push(command);
+        }
+        self.gap(at, to);
+        self.flush_run();
+    }
+
+    // A loop whose body runs from byte `from` to `to`
+    fn looped(&mut self, body: &[Node], from: usize, to: usize) {
+        if let Some(text) = self.inline(body, from, to) {
+            self.word(&text);
+            return;
+        }
+        self.end_line();
+        self.word("[");
+        self.closed = true;
+        self.depth += 1;
+        self.nodes(body, from, to);
+        self.end_line();
+        self.depth -= 1;
+        self.word("]");
+        self.closed = true;
+    }
+
+    // The loop as written on one line, if it can be: it holds no loop, no
+    // comment that would be lost, and fits
+    fn inline(&self, body: &[Node], from: usize, to: usize) -> Option<String> {
+        if self.layout.comments && self.source[from..to].iter().any(|&b| Token::from_byte(b).is_none() && !b.is_ascii_whitespace()) {
+            return None;
+        }
+        let mut text = String::from("[");
+        for node in body {
+            text.push(command(&node.kind)?);
+        }
+        text.push(']');
+        if text.len() <= self.room() {
+            Some(text)
+        } else {
+            None
+        }
+    }
+
+    // The text between commands from byte `from` to `to`. Its first line
+    // shares a line with what came before it.
+    fn gap(&mut self, from: usize, to: usize) {
+        if !self.layout.comments || from >= to {
+            return;
+        }
+        let text = String::from_utf8_lossy(&self.source[from..to]).into_owned();
+        let lines: Vec<&str> = text.split('\n').collect();
+        for (i, line) in lines.iter().enumerate() {
+            let line = line.trim();
+            if !line.is_empty() {
+                self.flush_run();
+                self.comment(line, i == 0);
+            } else if i > 0 && i < lines.len() - 1 {
+                self.flush_run();
+                self.end_line();
+                self.blank = true;
+            }
+        }
+    }
+
+    fn flush_run(&mut self) {
+        let run = std::mem::take(&mut self.run);
+        let room = self.room();
+        for chunk in run.as_bytes().chunks(room) {
+            // Commands are ASCII, so every chunk is whole characters
+            self.word(std::str::from_utf8(chunk).unwrap_or_default());
+        }
+    }
+
+    // Writes `word`, starting a line if this one is closed or has no room
+    fn word(&mut self, word: &str) {
+        if self.closed || (!self.line.is_empty() && self.line.len() + 1 + word.len() > self.layout.width) {
+            self.end_line();
+        }
+        if self.line.is_empty() {
+            self.start_line();
+        } else {
+            self.line.push(' ');
+        }
+        self.line.push_str(word);
+    }
+
+    fn comment(&mut self, text: &str, trailing: bool) {
+        if trailing && !self.line.is_empty() {
+            self.line.push(' ');
+        } else {
+            self.end_line();
+            self.start_line();
+        }
+        self.line.push_str(text);
+        self.closed = true;
+    }
+
+    fn start_line(&mut self) {
+        if self.blank && !self.out.is_empty() {
+            self.out.push('\n');
+        }
+        self.blank = false;
+        self.line.extend((0..self.depth * self.layout.indent).map(|_| ' '));
+    }
+
+    fn end_line(&mut self) {
+        if !self.line.is_empty() {
+            self.out.push_str(&self.line);
+            self.out.push('\n');
+            self.line.clear();
+        }
+        self.closed = false;
+    }
+
+    // Width left for commands after the indentation, at least one
+    fn room(&self) -> usize {
+        self.layout.width.saturating_sub(self.depth * self.layout.indent).max(1)
+    }
+}
+
+// The character for a node other than a loop or the end of the program
+fn command(kind: &NodeKind) -> Option<char> {
+    match kind {
+        NodeKind::MoveRight => Some('>'),
+        NodeKind::MoveLeft => Some('<'),
+        NodeKind::Increment => Some('+'),
+        NodeKind::Decrement => Some('-'),
+        NodeKind::Output => Some('.'),
+        NodeKind::Input => Some(','),
+        NodeKind::Loop(_) | NodeKind::Halt => None,
+    }
+}
+
+// Commands in the same group are written together
+fn group(command: char) -> u8 {
+    match command {
+        '+' | '-' => 0,
+        '<' | '>' => 1,
+        c => c as u8,
+    }
+}
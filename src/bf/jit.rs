@@ -0,0 +1,626 @@
+// Compiles Instructions to x86-64 machine code
+
+use super::cell::Cell;
+use super::common::{Instruction, Program, Span};
+use super::config::{Config, EofPolicy};
+use super::error::{Error, Result};
+use super::tape::Tape;
+use super::vm::failed_at;
+use Instruction::*;
+
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::ptr;
+
+// Registers
+const RAX: u8 = 0;
+const RDX: u8 = 2;
+const RBX: u8 = 3; // tape base address
+const RBP: u8 = 5;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+const R12: u8 = 12; // pointer, as a cell index
+const R13: u8 = 13; // tape length in cells
+const R14: u8 = 14; // &mut Frame
+const R15: u8 = 15; // scratch that survives calls
+
+// Condition codes for Jcc
+const JB: u8 = 0x2;
+const JZ: u8 = 0x4;
+const JNZ: u8 = 0x5;
+const JS: u8 = 0x8;
+
+// Frame field offsets
+const BASE: u8 = 0;
+const LEN: u8 = 8;
+const PTR: u8 = 16;
+const PC: u8 = 24;
+const INDEX_FN: u8 = 32;
+const READ_FN: u8 = 40;
+const WRITE_FN: u8 = 48;
+
+type IndexFn = extern "C" fn(*mut Frame, usize, isize) -> isize;
+type IoFn = extern "C" fn(*mut Frame, usize) -> i64;
+
+// The part of the run state generated code reads and writes directly
+#[repr(C)]
+struct Frame {
+    base: *mut u8,
+    len: usize,
+    ptr: usize,
+    // Instruction that last called out, for error positions
+    pc: usize,
+    index: IndexFn,
+    read: IoFn,
+    write: IoFn,
+}
+
+#[repr(C)]
+struct Context<'a, C: Cell, T: Read, U: Write> {
+    frame: Frame,
+    tape: Tape<C>,
+    input: &'a mut T,
+    output: &'a mut U,
+    eof_policy: EofPolicy,
+    error: Option<Error>,
+}
+
+impl<'a, C: Cell, T: Read, U: Write> Context<'a, C, T, U> {
+    // Publishes tape moves and reallocations to the generated code
+    fn sync(&mut self) {
+        self.frame.ptr = self.tape.ptr();
+        let cells = self.tape.cells_mut();
+        self.frame.len = cells.len();
+        self.frame.base = cells.as_mut_ptr() as *mut u8;
+    }
+}
+
+// Called when an index falls off the tape; returns the index or -1 on error
+extern "C" fn index<C: Cell, T: Read, U: Write>(frame: *mut Frame, ptr: usize, delta: isize) -> isize {
+    let ctx = unsafe { &mut *(frame as *mut Context<C, T, U>) };
+    ctx.tape.set_ptr(ptr);
+    match ctx.tape.index(delta) {
+        Ok(idx) => {
+            ctx.sync();
+            idx as isize
+        }
+        Err(e) => {
+            ctx.error = Some(e);
+            -1
+        }
+    }
+}
+
+extern "C" fn read<C: Cell, T: Read, U: Write>(frame: *mut Frame, ptr: usize) -> i64 {
+    let ctx = unsafe { &mut *(frame as *mut Context<C, T, U>) };
+    ctx.tape.set_ptr(ptr);
+    let mut b = [0u8; 1];
+    match ctx.input.read(&mut b) {
+        Ok(0) => ctx.tape.set(ctx.eof_policy.apply(ctx.tape.get())),
+        Ok(_) => ctx.tape.set(C::from_byte(b[0])),
+        Err(e) => {
+            ctx.error = Some(Error::Io(e, None));
+            return 1;
+        }
+    }
+    0
+}
+
+extern "C" fn write<C: Cell, T: Read, U: Write>(frame: *mut Frame, ptr: usize) -> i64 {
+    let ctx = unsafe { &mut *(frame as *mut Context<C, T, U>) };
+    ctx.tape.set_ptr(ptr);
+    match ctx.output.write_all(&[ctx.tape.get().to_byte()]) {
+        Ok(_) => 0,
+        Err(e) => {
+            ctx.error = Some(Error::Io(e, None));
+            1
+        }
+    }
+}
+
+// Where a rel32 jump lands once all code is emitted
+enum Label {
+    Instr(usize),
+    Exit,
+    Fail,
+}
+
+struct Assembler {
+    code: Vec<u8>,
+    // Cell size in bytes
+    size: u8,
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit_u32(&mut self, v: u32) {
+        self.emit(&v.to_le_bytes());
+    }
+
+    fn rex(&mut self, w: bool, r: u8, x: u8, b: u8) {
+        let rex = 0x40 | (w as u8) << 3 | (r >> 3) << 2 | (x >> 3) << 1 | b >> 3;
+        if rex != 0x40 {
+            self.emit(&[rex]);
+        }
+    }
+
+    fn modrm(&mut self, md: u8, reg: u8, rm: u8) {
+        self.emit(&[md << 6 | (reg & 7) << 3 | (rm & 7)]);
+    }
+
+    // Register to register, 64-bit: `op dst, src` for opcodes of the `op r/m64, r64` form
+    fn op_rr(&mut self, op: u8, dst: u8, src: u8) {
+        self.rex(true, src, 0, dst);
+        self.emit(&[op]);
+        self.modrm(3, src, dst);
+    }
+
+    fn mov_rr(&mut self, dst: u8, src: u8) {
+        self.op_rr(0x89, dst, src)
+    }
+
+    fn mov_ri(&mut self, dst: u8, imm: i64) {
+        if imm as i32 as i64 == imm {
+            self.rex(true, 0, 0, dst);
+            self.emit(&[0xc7]);
+            self.modrm(3, 0, dst);
+            self.emit_u32(imm as u32);
+        } else {
+            self.rex(true, 0, 0, dst);
+            self.emit(&[0xb8 + (dst & 7)]);
+            self.emit(&imm.to_le_bytes());
+        }
+    }
+
+    fn add_ri(&mut self, dst: u8, imm: i32) {
+        self.rex(true, 0, 0, dst);
+        self.emit(&[0x81]);
+        self.modrm(3, 0, dst);
+        self.emit_u32(imm as u32);
+    }
+
+    // `imul dst, src`
+    fn imul_rr(&mut self, dst: u8, src: u8) {
+        self.rex(true, dst, 0, src);
+        self.emit(&[0x0f, 0xaf]);
+        self.modrm(3, dst, src);
+    }
+
+    fn test_rr(&mut self, r: u8) {
+        self.op_rr(0x85, r, r)
+    }
+
+    fn xor_rr(&mut self, r: u8) {
+        self.op_rr(0x31, r, r)
+    }
+
+    // Frame fields, addressed as [r14 + disp8]
+    fn load_frame(&mut self, dst: u8, disp: u8) {
+        self.rex(true, dst, 0, R14);
+        self.emit(&[0x8b]);
+        self.modrm(1, dst, R14);
+        self.emit(&[disp]);
+    }
+
+    fn store_frame(&mut self, disp: u8, src: u8) {
+        self.rex(true, src, 0, R14);
+        self.emit(&[0x89]);
+        self.modrm(1, src, R14);
+        self.emit(&[disp]);
+    }
+
+    fn store_frame_imm(&mut self, disp: u8, imm: u32) {
+        self.rex(true, 0, 0, R14);
+        self.emit(&[0xc7]);
+        self.modrm(1, 0, R14);
+        self.emit(&[disp]);
+        self.emit_u32(imm);
+    }
+
+    fn call_frame(&mut self, disp: u8) {
+        self.rex(false, 0, 0, R14);
+        self.emit(&[0xff]);
+        self.modrm(1, 2, R14);
+        self.emit(&[disp]);
+    }
+
+    fn push(&mut self, r: u8) {
+        self.rex(false, 0, 0, r);
+        self.emit(&[0x50 + (r & 7)]);
+    }
+
+    fn pop(&mut self, r: u8) {
+        self.rex(false, 0, 0, r);
+        self.emit(&[0x58 + (r & 7)]);
+    }
+
+    // Operand [rbx + idx * size] with an operand of `bytes` bytes
+    fn cell_op(&mut self, opcode: &[u8], bytes: u8, reg: u8, idx: u8) {
+        if bytes == 2 {
+            self.emit(&[0x66]);
+        }
+        self.rex(bytes == 8, reg, idx, RBX);
+        self.emit(opcode);
+        self.modrm(0, reg, 4);
+        let scale = self.size.trailing_zeros() as u8;
+        self.emit(&[scale << 6 | (idx & 7) << 3 | RBX]);
+    }
+
+    // Zero-extending load of the cell at index `idx` into `dst`
+    fn load_cell(&mut self, dst: u8, idx: u8) {
+        match self.size {
+            1 => self.cell_op(&[0x0f, 0xb6], 4, dst, idx),
+            2 => self.cell_op(&[0x0f, 0xb7], 4, dst, idx),
+            4 => self.cell_op(&[0x8b], 4, dst, idx),
+            _ => self.cell_op(&[0x8b], 8, dst, idx),
+        }
+    }
+
+    fn store_cell(&mut self, idx: u8, src: u8) {
+        let opcode = if self.size == 1 { 0x88 } else { 0x89 };
+        self.cell_op(&[opcode], self.size, src, idx)
+    }
+
+    fn add_cell(&mut self, idx: u8, src: u8) {
+        let opcode = if self.size == 1 { 0x00 } else { 0x01 };
+        self.cell_op(&[opcode], self.size, src, idx)
+    }
+
+    // Jumps with a rel32 to be patched; returns the patch location
+    fn jcc(&mut self, cc: u8) -> usize {
+        self.emit(&[0x0f, 0x80 | cc]);
+        self.emit_u32(0);
+        self.code.len() - 4
+    }
+
+    fn jmp(&mut self) -> usize {
+        self.emit(&[0xe9]);
+        self.emit_u32(0);
+        self.code.len() - 4
+    }
+
+    fn patch(&mut self, at: usize, target: usize) {
+        let rel = (target as isize - (at as isize + 4)) as i32;
+        self.code[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+
+    fn patch_here(&mut self, at: usize) {
+        let here = self.code.len();
+        self.patch(at, here)
+    }
+
+    fn jcc_back(&mut self, cc: Option<u8>, target: usize) {
+        let at = match cc {
+            Some(cc) => self.jcc(cc),
+            None => self.jmp(),
+        };
+        self.patch(at, target)
+    }
+
+    fn jcc_label(&mut self, cc: u8, label: Label) {
+        let at = self.jcc(cc);
+        self.fixups.push((at, label))
+    }
+
+    // Calls a Frame function with the pointer as its second argument
+    fn call_out(&mut self, func: u8, pc: usize) {
+        self.call_out_at(func, pc, R12)
+    }
+
+    // As `call_out`, passing the cell index in `ptr` for the pointer
+    fn call_out_at(&mut self, func: u8, pc: usize, ptr: u8) {
+        self.store_frame_imm(PC, pc as u32);
+        self.mov_rr(RDI, R14);
+        self.mov_rr(RSI, ptr);
+        self.call_frame(func);
+    }
+
+    // Index `delta` cells from the pointer into rax, calling out when it leaves the tape
+    fn index(&mut self, delta: isize, pc: usize) {
+        self.mov_rr(RAX, R12);
+        self.add_ri(RAX, delta as i32);
+        self.op_rr(0x39, RAX, R13);
+        let on_tape = self.jcc(JB);
+        self.mov_ri(RDX, delta as i64);
+        self.call_out(INDEX_FN, pc);
+        self.test_rr(RAX);
+        self.jcc_label(JS, Label::Fail);
+        self.load_frame(RBX, BASE);
+        self.load_frame(R13, LEN);
+        self.load_frame(R12, PTR);
+        self.patch_here(on_tape);
+    }
+
+    // Register holding the index of the cell `offset` from the pointer: r12
+    // itself, or rax
+    fn cell_at(&mut self, offset: i32, pc: usize) -> u8 {
+        if offset == 0 {
+            return R12;
+        }
+        self.index(offset as isize, pc);
+        RAX
+    }
+
+    fn move_by(&mut self, delta: isize, pc: usize) {
+        self.index(delta, pc);
+        self.mov_rr(R12, RAX);
+    }
+
+    // `[->+<]` and friends: add the current cell to the one `delta` away, then zero it
+    fn add_move(&mut self, delta: isize, pc: usize) {
+        self.load_cell(RAX, R12);
+        self.test_rr(RAX);
+        let skip = self.jcc(JZ);
+        self.index(delta, pc);
+        self.load_cell(RDX, R12);
+        self.add_cell(RAX, RDX);
+        self.xor_rr(RDX);
+        self.store_cell(R12, RDX);
+        self.patch_here(skip);
+    }
+
+    // Adds the current cell times `factor` to the one `delta` away, unless it is zero
+    fn mul_add(&mut self, delta: isize, factor: i64, pc: usize) {
+        self.load_cell(RAX, R12);
+        self.test_rr(RAX);
+        let skip = self.jcc(JZ);
+        self.index(delta, pc);
+        self.load_cell(RDX, R12);
+        self.mov_ri(R15, factor);
+        self.imul_rr(RDX, R15);
+        self.add_cell(RAX, RDX);
+        self.patch_here(skip);
+    }
+
+    fn instruction(&mut self, instr: Instruction, pc: usize) {
+        match instr {
+            Add(offset, amt) => {
+                let idx = self.cell_at(offset, pc);
+                self.mov_ri(RDX, amt);
+                self.add_cell(idx, RDX);
+            }
+            Set(offset, v) => {
+                let idx = self.cell_at(offset, pc);
+                self.mov_ri(RDX, v);
+                self.store_cell(idx, RDX);
+            }
+            Zero => {
+                self.xor_rr(RAX);
+                self.store_cell(R12, RAX);
+            }
+            Jez(dst) => {
+                self.load_cell(RAX, R12);
+                self.test_rr(RAX);
+                self.jcc_label(JZ, Label::Instr(pc + dst as usize + 1));
+            }
+            Jnz(dst) => {
+                self.load_cell(RAX, R12);
+                self.test_rr(RAX);
+                self.jcc_label(JNZ, Label::Instr(pc + 1 - dst as usize));
+            }
+            Right(n) => self.move_by(n as isize, pc),
+            Left(n) => self.move_by(-(n as isize), pc),
+            SearchRight(n) | SearchLeft(n) => {
+                let delta = if let SearchRight(_) = instr { n as isize } else { -(n as isize) };
+                let top = self.code.len();
+                self.load_cell(RAX, R12);
+                self.test_rr(RAX);
+                let done = self.jcc(JZ);
+                self.move_by(delta, pc);
+                self.jcc_back(None, top);
+                self.patch_here(done);
+            }
+            AddMoveRight(n) => self.add_move(n as isize, pc),
+            AddMoveLeft(n) => self.add_move(-(n as isize), pc),
+            MulAdd(offset, factor) => self.mul_add(offset as isize, factor, pc),
+            ZeroRight(0) | ZeroLeft(0) => {}
+            ZeroRight(n) | ZeroLeft(n) => {
+                let delta = if let ZeroRight(_) = instr { 1 } else { -1 };
+                // The far end is checked first, as in the VM, so that nothing
+                // is cleared when the run leaves the tape
+                self.index(delta * n as isize, pc);
+                self.mov_ri(R15, n as i64);
+                let top = self.code.len();
+                self.xor_rr(RAX);
+                self.store_cell(R12, RAX);
+                self.move_by(delta, pc);
+                self.add_ri(R15, -1);
+                self.jcc_back(Some(JNZ), top);
+            }
+            Mandel(x, n) => {
+                let top = self.code.len();
+                self.load_cell(RAX, R12);
+                self.test_rr(RAX);
+                let done = self.jcc(JZ);
+                self.index(x as isize, pc);
+                self.mov_rr(R15, RAX);
+                self.load_cell(RAX, R15);
+                self.test_rr(RAX);
+                let skip = self.jcc(JZ);
+                self.index(x as isize + n as isize, pc);
+                // The call may have moved the tape
+                self.load_cell(RDX, R15);
+                self.add_cell(RAX, RDX);
+                self.xor_rr(RDX);
+                self.store_cell(R15, RDX);
+                self.patch_here(skip);
+                self.move_by(-(n as isize), pc);
+                self.jcc_back(None, top);
+                self.patch_here(done);
+            }
+            Write(offset) => {
+                let idx = self.cell_at(offset, pc);
+                self.call_out_at(WRITE_FN, pc, idx);
+                self.test_rr(RAX);
+                self.jcc_label(JNZ, Label::Fail);
+            }
+            Read => {
+                self.call_out(READ_FN, pc);
+                self.test_rr(RAX);
+                self.jcc_label(JNZ, Label::Fail);
+            }
+            Halt => {
+                self.xor_rr(RAX);
+                let at = self.jmp();
+                self.fixups.push((at, Label::Exit));
+            }
+        }
+    }
+}
+
+// Saves callee-saved registers and loads the frame, whose address is the only argument
+const SAVED: [u8; 6] = [RBX, RBP, R12, R13, R14, R15];
+
+fn assemble(instructions: &[Instruction], size: u8) -> Vec<u8> {
+    let mut asm = Assembler {
+        code: Vec::new(),
+        size,
+        fixups: Vec::new(),
+    };
+    for &r in SAVED.iter() {
+        asm.push(r);
+    }
+    // Keep the stack 16-byte aligned at calls
+    asm.add_ri(4, -8);
+    asm.mov_rr(R14, RDI);
+    asm.load_frame(RBX, BASE);
+    asm.load_frame(R13, LEN);
+    asm.load_frame(R12, PTR);
+
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    for (pc, &instr) in instructions.iter().enumerate() {
+        offsets.push(asm.code.len());
+        asm.instruction(instr, pc);
+    }
+
+    // Running off the end behaves like Halt
+    offsets.push(asm.code.len());
+    asm.xor_rr(RAX);
+    let exit = asm.jmp();
+    let fail = asm.code.len();
+    asm.mov_ri(RAX, 1);
+    asm.patch_here(exit);
+    let epilogue = asm.code.len();
+    asm.store_frame(PTR, R12);
+    asm.add_ri(4, 8);
+    for &r in SAVED.iter().rev() {
+        asm.pop(r);
+    }
+    asm.emit(&[0xc3]);
+
+    for (at, label) in std::mem::take(&mut asm.fixups) {
+        let target = match label {
+            Label::Instr(i) => offsets[i],
+            Label::Exit => epilogue,
+            Label::Fail => fail,
+        };
+        asm.patch(at, target);
+    }
+    asm.code
+}
+
+// Executable copy of generated code
+struct Code {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Code {
+    fn new(code: &[u8]) -> io::Result<Self> {
+        let len = code.len();
+        unsafe {
+            let ptr = libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            let ptr = ptr as *mut u8;
+            ptr::copy_nonoverlapping(code.as_ptr(), ptr, len);
+            let code = Self { ptr, len };
+            if libc::mprotect(ptr as *mut _, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(code)
+        }
+    }
+}
+
+impl Drop for Code {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut _, self.len);
+        }
+    }
+}
+
+/// A program compiled to native code for cells of type `C`
+pub struct Jit<C: Cell> {
+    code: Code,
+    instructions: Vec<Instruction>,
+    spans: Vec<Span>,
+    config: Config,
+    cell: PhantomData<C>,
+}
+
+impl<C: Cell> Jit<C> {
+    /// Compiles `program` for `config`, which must not set any limits; the
+    /// generated code does not count its work
+    pub fn compile(program: &Program, config: Config) -> Result<Self> {
+        if !config.limits.is_unlimited() {
+            return Err(Error::Unsupported("the JIT cannot enforce limits".to_string()));
+        }
+        let size = (C::WIDTH.bits() / 8) as u8;
+        let code = Code::new(&assemble(&program.instructions, size)).map_err(|e| Error::Io(e, None))?;
+        Ok(Self {
+            code,
+            instructions: program.instructions.clone(),
+            spans: program.spans.clone(),
+            config,
+            cell: PhantomData,
+        })
+    }
+
+    /// Runs the program on a fresh tape
+    pub fn run<T: Read, U: Write>(&self, input: &mut T, output: &mut U) -> Result<()> {
+        let mut ctx = Context {
+            frame: Frame {
+                base: ptr::null_mut(),
+                len: 0,
+                ptr: 0,
+                pc: 0,
+                index: index::<C, T, U>,
+                read: read::<C, T, U>,
+                write: write::<C, T, U>,
+            },
+            tape: Tape::new(self.config.tape_size, self.config.tape_policy),
+            input,
+            output,
+            eof_policy: self.config.eof_policy,
+            error: None,
+        };
+        ctx.sync();
+
+        let ctx_ptr: *mut Context<C, T, U> = &mut ctx;
+        let status = unsafe {
+            let entry: extern "C" fn(*mut Frame) -> i64 = std::mem::transmute(self.code.ptr);
+            entry(ctx_ptr as *mut Frame)
+        };
+        if status == 0 {
+            return Ok(());
+        }
+        let e = ctx.error.take().expect("generated code failed without an error");
+        let pc = ctx.frame.pc;
+        let pos = self.spans.get(pc).map(|&span| failed_at(self.instructions[pc], span, &ctx.tape, &e));
+        Err(e.or_at(pos))
+    }
+}
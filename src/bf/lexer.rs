@@ -1,48 +1,158 @@
 // Takes input text and converts to Tokens
 
-use super::common::Token::*;
+use super::common::Token::{self, Eof};
+use super::common::{Lexeme, Position};
+use super::error::{Error, Result};
 
-use std::io::Read;
+use std::io::{ErrorKind, Read};
 
 pub struct Lexer<T: Read> {
     source: T,
     eof: bool,
+    pos: Position,
 }
 
 impl<T: Read> Lexer<T> {
     pub fn new(source: T) -> Self {
-        Self { source, eof: false }
+        Self {
+            source,
+            eof: false,
+            pos: Default::default(),
+        }
+    }
+
+    /// Turns this into a lexer that keeps the comments and whitespace it would
+    /// skip, attached to the tokens around them
+    pub fn lossless(self) -> Lossless<T> {
+        Lossless {
+            lexer: self,
+            peeked: None,
+        }
+    }
+
+    // Position of the byte about to be read, moved past it
+    fn advance(&mut self, b: u8) -> Position {
+        let pos = self.pos;
+        self.pos.offset += 1;
+        if b == b'\n' {
+            self.pos.line += 1;
+            self.pos.column = 1;
+        } else {
+            self.pos.column += 1;
+        }
+        pos
+    }
+
+    // The next byte and its position, None at the end of the source
+    fn read_byte(&mut self) -> Option<Result<(u8, Position)>> {
+        let mut b = [0u8; 1];
+        loop {
+            return match self.source.read(&mut b) {
+                Ok(0) => None,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => Some(Err(Error::Io(e, Some(self.pos)))),
+                Ok(_) => Some(Ok((b[0], self.advance(b[0])))),
+            };
+        }
     }
 }
 
 impl<T: Read> std::iter::Iterator for Lexer<T> {
-    type Item = super::common::Token;
+    type Item = Result<(Token, Position)>;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.eof {
             return None;
         }
-        let mut b = [0u8; 1];
-        loop {
-            match self.source.read(&mut b) {
-                Ok(0) => {}
-                Err(_) => {}
-                Ok(_) => {
-                    return Some(match b[0] {
-                        b'>' => MoveRight,
-                        b'<' => MoveLeft,
-                        b'+' => Increment,
-                        b'-' => Decrement,
-                        b'.' => Output,
-                        b',' => Input,
-                        b'[' => LeftLoop,
-                        b']' => RightLoop,
-                        _ => continue,
-                    })
+        while let Some(next) = self.read_byte() {
+            match next {
+                Ok((b, pos)) => {
+                    if let Some(token) = Token::from_byte(b) {
+                        return Some(Ok((token, pos)));
+                    }
                 }
+                Err(e) => {
+                    self.eof = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.eof = true;
+        Some(Ok((Eof, self.pos)))
+    }
+}
+
+/// A `Lexer` that yields each token as a `Lexeme` with the text around it.
+/// A token's trailing trivia runs to the end of its line or the next token,
+/// whichever comes first; everything else before a token is its leading
+/// trivia, and the `Eof` lexeme leads with whatever follows the last command.
+pub struct Lossless<T: Read> {
+    lexer: Lexer<T>,
+    // A byte read while looking for the end of trailing trivia
+    peeked: Option<(u8, Position)>,
+}
+
+impl<T: Read> Lossless<T> {
+    fn read_byte(&mut self) -> Option<Result<(u8, Position)>> {
+        match self.peeked.take() {
+            Some(next) => Some(Ok(next)),
+            None => self.lexer.read_byte(),
+        }
+    }
+
+    // Bytes up to the end of the line or the next command, which are left unread
+    fn trailing(&mut self) -> Result<Vec<u8>> {
+        let mut trailing = Vec::new();
+        while let Some(next) = self.read_byte() {
+            let (b, pos) = next?;
+            if b == b'\n' || Token::from_byte(b).is_some() {
+                self.peeked = Some((b, pos));
+                break;
             }
-            self.eof = true;
-            return Some(EOF);
+            trailing.push(b);
+        }
+        Ok(trailing)
+    }
+
+    fn fail(&mut self, e: Error) -> Option<Result<Lexeme>> {
+        self.lexer.eof = true;
+        Some(Err(e))
+    }
+}
+
+impl<T: Read> std::iter::Iterator for Lossless<T> {
+    type Item = Result<Lexeme>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.lexer.eof {
+            return None;
         }
+        let mut leading = Vec::new();
+        let (token, pos) = loop {
+            match self.read_byte() {
+                Some(Ok((b, pos))) => match Token::from_byte(b) {
+                    Some(token) => break (token, pos),
+                    None => leading.push(b),
+                },
+                Some(Err(e)) => return self.fail(e),
+                None => {
+                    self.lexer.eof = true;
+                    break (Eof, self.lexer.pos);
+                }
+            }
+        };
+        let trailing = match token {
+            Eof => Vec::new(),
+            _ => match self.trailing() {
+                Ok(trailing) => trailing,
+                Err(e) => return self.fail(e),
+            },
+        };
+        Some(Ok(Lexeme {
+            token,
+            pos,
+            leading,
+            trailing,
+        }))
     }
 }
@@ -0,0 +1,168 @@
+// Caps on how much work a program may do, for running untrusted code
+
+use super::error::{Error, Result};
+
+use std::fmt;
+
+/// Caps on a single run. `None` means no cap. A run that would go past one
+/// stops with `Error::LimitExceeded` before doing so.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Limits {
+    /// Instructions run. Fused instructions that loop internally, like a whole
+    /// `[>]`, also count each pass through the loop.
+    pub steps: Option<u64>,
+    /// Bytes written
+    pub output: Option<u64>,
+    /// Cells from the leftmost to the rightmost the program has used
+    pub cells: Option<usize>,
+}
+
+impl Limits {
+    pub fn is_unlimited(&self) -> bool {
+        *self == Limits::default()
+    }
+
+    /// The first of these limits `usage` has already gone past, if any
+    pub(crate) fn passed_by(&self, usage: Usage) -> Option<Limit> {
+        if self.steps.is_some_and(|max| usage.steps > max) {
+            Some(Limit::Steps)
+        } else if self.output.is_some_and(|max| usage.output > max) {
+            Some(Limit::Output)
+        } else if self.cells.is_some_and(|max| usage.cells > max) {
+            Some(Limit::Cells)
+        } else {
+            None
+        }
+    }
+}
+
+/// Which of the `Limits` a run hit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Limit {
+    Steps,
+    Output,
+    Cells,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Steps => write!(f, "step"),
+            Limit::Output => write!(f, "output"),
+            Limit::Cells => write!(f, "tape cell"),
+        }
+    }
+}
+
+/// How much of each limit a run has used so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Usage {
+    pub steps: u64,
+    pub output: u64,
+    pub cells: usize,
+}
+
+// Counts usage against the limits. Unset limits are the largest value, so
+// counting costs the same with or without them.
+pub(crate) struct Meter {
+    max_steps: u64,
+    max_output: u64,
+    max_cells: usize,
+    usage: Usage,
+    // Leftmost and rightmost cells used, numbered from where the tape started
+    lo: isize,
+    hi: isize,
+}
+
+impl Meter {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            max_steps: limits.steps.unwrap_or(u64::MAX),
+            max_output: limits.output.unwrap_or(u64::MAX),
+            max_cells: limits.cells.unwrap_or(usize::MAX),
+            usage: Usage { cells: 1, ..Usage::default() },
+            lo: 0,
+            hi: 0,
+        }
+    }
+
+    /// A meter that has already counted `usage`, with `range` the leftmost
+    /// and rightmost cells used
+    pub fn resume(limits: Limits, usage: Usage, range: (isize, isize)) -> Self {
+        Self {
+            usage,
+            lo: range.0,
+            hi: range.1,
+            ..Self::new(limits)
+        }
+    }
+
+    pub fn usage(&self) -> Usage {
+        self.usage
+    }
+
+    pub fn range(&self) -> (isize, isize) {
+        (self.lo, self.hi)
+    }
+
+    #[inline(always)]
+    pub fn step(&mut self) -> Result<()> {
+        if self.usage.steps >= self.max_steps {
+            return Err(Error::LimitExceeded(Limit::Steps, None));
+        }
+        self.usage.steps += 1;
+        Ok(())
+    }
+
+    /// Counts `n` steps at once, or none if that would pass the limit
+    #[inline(always)]
+    pub fn steps(&mut self, n: u64) -> Result<()> {
+        if self.max_steps.saturating_sub(self.usage.steps) < n {
+            return Err(Error::LimitExceeded(Limit::Steps, None));
+        }
+        self.usage.steps += n;
+        Ok(())
+    }
+
+    /// Takes the step and output counts back to `usage`, for an instruction
+    /// that will run again. Cells used stay counted, as the tape keeps them.
+    #[cold]
+    pub fn rewind(&mut self, usage: Usage) {
+        self.usage.steps = usage.steps;
+        self.usage.output = usage.output;
+    }
+
+    #[inline(always)]
+    pub fn output(&mut self) -> Result<()> {
+        if self.usage.output >= self.max_output {
+            return Err(Error::LimitExceeded(Limit::Output, None));
+        }
+        self.usage.output += 1;
+        Ok(())
+    }
+
+    /// Records a use of cell `idx` on a tape that has grown by `origin` cells
+    /// on the left
+    #[inline(always)]
+    pub fn touch(&mut self, idx: usize, origin: usize) -> Result<()> {
+        let cell = idx as isize - origin as isize;
+        if cell < self.lo || cell > self.hi {
+            self.widen(cell)
+        } else {
+            Ok(())
+        }
+    }
+
+    #[cold]
+    fn widen(&mut self, cell: isize) -> Result<()> {
+        let (lo, hi) = (self.lo.min(cell), self.hi.max(cell));
+        let cells = (hi - lo) as usize + 1;
+        if cells > self.max_cells {
+            return Err(Error::LimitExceeded(Limit::Cells, None));
+        }
+        self.lo = lo;
+        self.hi = hi;
+        self.usage.cells = cells;
+        Ok(())
+    }
+}
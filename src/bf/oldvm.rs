@@ -1,123 +1,135 @@
 #![allow(dead_code)]
 
-use super::common::Token;
+use super::cell::Cell;
+use super::common::{Position, Token};
+use super::config::{Config, EofPolicy};
+use super::error::{Error, Result};
+use super::limits::{Meter, Usage};
+use super::tape::Tape;
 use Token::*;
 
 use std::io::{Read, Write};
 use std::iter::Iterator;
 
-use std::result::Result;
-
-pub struct BFInterpreter<'a, T: Read, U: Write, V: Iterator<Item = Token>> {
+pub struct BFInterpreter<'a, C: Cell, T: Read, U: Write, V: Iterator<Item = Result<(Token, Position)>>> {
     read_stream: &'a mut T,
     write_stream: &'a mut U,
     token_source: V,
-    token_buffer: Vec<Token>,
-    memory: Vec<u8>,
-    mem_idx: usize,
+    token_buffer: Vec<(Token, Position)>,
+    tape: Tape<C>,
+    eof_policy: EofPolicy,
+    meter: Meter,
     stack: Vec<usize>,
     pc: usize,
 }
 
-impl<'a, T: Read, U: Write, V: Iterator<Item = Token>> BFInterpreter<'a, T, U, V> {
+impl<'a, T: Read, U: Write, V: Iterator<Item = Result<(Token, Position)>>> BFInterpreter<'a, u8, T, U, V> {
     pub fn new(src: V, input: &'a mut T, output: &'a mut U) -> Self {
         Self::with_capacity(src, input, output, 30000)
     }
 
     pub fn with_capacity(src: V, input: &'a mut T, output: &'a mut U, capacity: usize) -> Self {
+        Self::with_config(src, input, output, Config::new().tape_size(capacity))
+    }
+}
+
+impl<'a, C: Cell, T: Read, U: Write, V: Iterator<Item = Result<(Token, Position)>>> BFInterpreter<'a, C, T, U, V> {
+    pub fn with_config(src: V, input: &'a mut T, output: &'a mut U, config: Config) -> Self {
         Self {
             read_stream: input,
             write_stream: output,
             token_source: src,
             token_buffer: Vec::new(),
-            memory: vec![0; capacity],
-            mem_idx: 0,
+            tape: Tape::new(config.tape_size, config.tape_policy),
+            eof_policy: config.eof_policy,
+            meter: Meter::new(config.limits),
             stack: Vec::new(),
             pc: 0,
         }
     }
 
+    // A token stream that stops without an Eof token is treated as if it had one
     #[inline]
-    fn read_tokens(&mut self, n: usize) -> Result<usize, ()> {
+    fn read_tokens(&mut self, n: usize) -> Result<usize> {
         for _ in 0..n {
-            if let Some(i) = self.token_source.next() {
-                self.token_buffer.push(i)
-            } else {
-                return Err(());
+            match self.token_source.next() {
+                Some(t) => self.token_buffer.push(t?),
+                None => {
+                    let pos = self.token_buffer.last().map(|t| t.1).unwrap_or_default();
+                    self.token_buffer.push((Eof, pos));
+                }
             }
         }
         Ok(n)
     }
 
-    fn get_token(&mut self, idx: usize) -> Result<&Token, ()> {
+    fn get_token(&mut self, idx: usize) -> Result<(Token, Position)> {
         if self.token_buffer.len() < (idx + 1) {
-            match self.read_tokens((idx + 1) - self.token_buffer.len()) {
-                Ok(_) => {}
-                Err(()) => return Err(()),
-            }
+            self.read_tokens((idx + 1) - self.token_buffer.len())?;
         }
-        Ok(&self.token_buffer[idx])
+        Ok(self.token_buffer[idx])
+    }
+
+    fn move_by(&mut self, delta: isize) -> Result<()> {
+        self.tape.move_by(delta)?;
+        self.meter.touch(self.tape.ptr(), self.tape.origin())
+    }
+
+    /// How much of each limit in the config the run has used so far
+    pub fn usage(&self) -> Usage {
+        self.meter.usage()
+    }
+
+    /// Index of the cell under the data pointer
+    pub fn ptr(&self) -> usize {
+        self.tape.ptr()
+    }
+
+    pub fn tape(&self) -> &[C] {
+        self.tape.cells()
     }
 
-    pub fn run(&mut self) -> Result<(), &'static str> {
+    pub fn run(&mut self) -> Result<()> {
         loop {
-            let ins = match self.get_token(self.pc) {
-                Ok(i) => i,
-                Err(_) => return Err("Failed to read Token."),
-            };
+            let (ins, pos) = self.get_token(self.pc)?;
+            if ins != Eof {
+                self.meter.step().map_err(|e| e.or_at(Some(pos)))?;
+            }
             match ins {
-                MoveRight => {
-                    if self.mem_idx == self.memory.len() - 1 {
-                        self.mem_idx = 0;
-                    } else {
-                        self.mem_idx += 1;
-                    }
-                }
-                MoveLeft => {
-                    if self.mem_idx == 0 {
-                        self.mem_idx = self.memory.len() - 1;
-                    } else {
-                        self.mem_idx -= 1;
-                    }
-                }
+                MoveRight => self.move_by(1).map_err(|e| e.or_at(Some(pos)))?,
+                MoveLeft => self.move_by(-1).map_err(|e| e.or_at(Some(pos)))?,
                 Increment => {
-                    self.memory[self.mem_idx] = self.memory[self.mem_idx].wrapping_add(1);
+                    self.tape.set(self.tape.get().wrapping_add(C::from_i64(1)));
                 }
                 Decrement => {
-                    self.memory[self.mem_idx] = self.memory[self.mem_idx].wrapping_sub(1);
+                    self.tape.set(self.tape.get().wrapping_add(C::from_i64(-1)));
                 }
                 Output => {
-                    match self
+                    self.meter.output().map_err(|e| e.or_at(Some(pos)))?;
+                    if let Err(e) = self
                         .write_stream
-                        .write(&self.memory[self.mem_idx..self.mem_idx + 1])
+                        .write_all(&[self.tape.get().to_byte()])
                     {
-                        Ok(_) => {}
-                        Err(_) => return Err("Failed to write to output."),
+                        return Err(Error::Io(e, Some(pos)));
                     }
                 }
                 Input => {
-                    match self
-                        .read_stream
-                        .read(&mut self.memory[self.mem_idx..self.mem_idx + 1])
-                    {
-                        Ok(_) => {}
-                        Err(_) => return Err("Failed to read from input."),
+                    let mut b = [0u8; 1];
+                    match self.read_stream.read(&mut b) {
+                        Ok(0) => self.tape.set(self.eof_policy.apply(self.tape.get())),
+                        Ok(_) => self.tape.set(C::from_byte(b[0])),
+                        Err(e) => return Err(Error::Io(e, Some(pos))),
                     }
                 }
                 LeftLoop => {
-                    if self.memory[self.mem_idx] == 0 {
+                    if self.tape.get().is_zero() {
                         // jump forward
                         let mut count = 1;
                         while count != 0 {
                             self.pc += 1;
-                            let stru = match self.get_token(self.pc) {
-                                Ok(i) => i,
-                                Err(_) => return Err("Failed to read Token."),
-                            };
+                            let (stru, _) = self.get_token(self.pc)?;
                             match stru {
-                                EOF => {
-                                    return Err("Reached EOF while searching for ]. Unmatched [.")
-                                }
+                                Eof => return Err(Error::UnmatchedOpen(pos)),
                                 LeftLoop => count += 1,
                                 RightLoop => count -= 1,
                                 _ => continue,
@@ -131,9 +143,9 @@ impl<'a, T: Read, U: Write, V: Iterator<Item = Token>> BFInterpreter<'a, T, U, V
                 RightLoop => {
                     // Establish that stack has an element
                     if self.stack.is_empty() {
-                        return Err("Stack is unexpectedly empty. Unmatched ].");
+                        return Err(Error::UnmatchedClose(pos));
                     }
-                    if self.memory[self.mem_idx] != 0 {
+                    if !self.tape.get().is_zero() {
                         // jump backward
                         self.pc = self.stack[self.stack.len() - 1];
                     } else {
@@ -141,7 +153,7 @@ impl<'a, T: Read, U: Write, V: Iterator<Item = Token>> BFInterpreter<'a, T, U, V
                         self.stack.pop().unwrap();
                     }
                 }
-                EOF => return Ok(()),
+                Eof => return Ok(()),
             }
             self.pc += 1;
         }
@@ -0,0 +1,117 @@
+// Which optimizations code generation applies
+
+use std::fmt;
+use std::str::FromStr;
+
+/// One of the optimizations `generate_code_with` can apply
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pass {
+    /// `[-]` and `[+]` become a Zero
+    Zero,
+    /// `[>]` and `[<]` become a SearchRight or SearchLeft
+    Search,
+    /// Loops that only add and move, like `[->++<]`, become MulAdds or an AddMove
+    Linear,
+    /// Loops like `[>[->+<]<<]`, common in mandelbrot.bf, become a Mandel.
+    /// Needs `Linear` for the inner loop.
+    Mandel,
+    /// Moves are folded into the offsets of adds, sets and writes, and adds
+    /// and sets to the same cell are merged
+    Offsets,
+    /// `[-]>[-]>[-]` and `<[-]<[-]<[-]` become a ZeroRight or ZeroLeft
+    ZeroRuns,
+}
+
+impl Pass {
+    pub const ALL: [Pass; 6] = [
+        Pass::Zero,
+        Pass::Search,
+        Pass::Linear,
+        Pass::Mandel,
+        Pass::Offsets,
+        Pass::ZeroRuns,
+    ];
+
+    /// Name of the pass on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            Pass::Zero => "zero",
+            Pass::Search => "search",
+            Pass::Linear => "linear",
+            Pass::Mandel => "mandel",
+            Pass::Offsets => "offsets",
+            Pass::ZeroRuns => "zero-runs",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl fmt::Display for Pass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Pass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Pass::ALL
+            .iter()
+            .copied()
+            .find(|pass| pass.name() == s)
+            .ok_or_else(|| format!("unknown pass `{}`", s))
+    }
+}
+
+/// The passes code generation applies. The default is level 2, every pass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OptConfig {
+    passes: u8,
+}
+
+impl Default for OptConfig {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl OptConfig {
+    /// No passes: each run of moves or adds becomes one instruction, and
+    /// loops stay loops
+    pub fn none() -> Self {
+        Self { passes: 0 }
+    }
+
+    pub fn all() -> Self {
+        Pass::ALL.iter().fold(Self::none(), |opt, &pass| opt.enable(pass))
+    }
+
+    /// Passes at an optimization level: 0 is none, 1 every pass but the ones
+    /// made for a particular program, and 2 all of them. None above 2.
+    pub fn level(level: u8) -> Option<Self> {
+        match level {
+            0 => Some(Self::none()),
+            1 => Some(Self::all().disable(Pass::Mandel)),
+            2 => Some(Self::all()),
+            _ => None,
+        }
+    }
+
+    pub fn enable(mut self, pass: Pass) -> Self {
+        self.passes |= pass.bit();
+        self
+    }
+
+    pub fn disable(mut self, pass: Pass) -> Self {
+        self.passes &= !pass.bit();
+        self
+    }
+
+    pub fn enabled(&self, pass: Pass) -> bool {
+        self.passes & pass.bit() != 0
+    }
+}
@@ -1,56 +1,85 @@
 // Takes tokens and converts to Nodes in a tree
 
-use super::common::{Node, Token};
+use super::common::{Node, NodeKind, Position, Span, Token};
+use super::error::{Error, Result};
 
 use std::iter::Iterator;
 
-pub struct Parser<T: Iterator<Item = Token>> {
+pub struct Parser<T: Iterator<Item = Result<(Token, Position)>>> {
     tokens: T,
     eof: bool,
+    last: Position,
 }
 
-impl<T: Iterator<Item = Token>> Parser<T> {
+impl<T: Iterator<Item = Result<(Token, Position)>>> Parser<T> {
     pub fn new(tokens: T) -> Self {
-        Self { tokens, eof: false }
+        Self {
+            tokens,
+            eof: false,
+            last: Default::default(),
+        }
+    }
+
+    // A token stream that stops without an Eof token is treated as if it had one
+    fn next_token(&mut self) -> Result<(Token, Position)> {
+        match self.tokens.next() {
+            Some(Ok((t, pos))) => {
+                self.last = pos;
+                Ok((t, pos))
+            }
+            Some(Err(e)) => Err(e),
+            None => Ok((Token::Eof, self.last)),
+        }
     }
 
-    fn get_node(&mut self) -> Option<Node> {
-        if let Some(t) = self.tokens.next() {
-            Some(match t {
-                Token::MoveRight => Node::MoveRight,
-                Token::MoveLeft => Node::MoveLeft,
-                Token::Increment => Node::Increment,
-                Token::Decrement => Node::Decrement,
-                Token::Output => Node::Output,
-                Token::Input => Node::Input,
-                Token::EOF => {
+    // `open` is the position of the innermost enclosing `[`, whose `]` yields None
+    fn get_node(&mut self, open: Option<Position>) -> Result<Option<Node>> {
+        let (t, pos) = self.next_token()?;
+        let mut span = Span::byte(pos);
+        let kind = match t {
+            Token::MoveRight => NodeKind::MoveRight,
+            Token::MoveLeft => NodeKind::MoveLeft,
+            Token::Increment => NodeKind::Increment,
+            Token::Decrement => NodeKind::Decrement,
+            Token::Output => NodeKind::Output,
+            Token::Input => NodeKind::Input,
+            Token::Eof => match open {
+                Some(open) => return Err(Error::UnmatchedOpen(open)),
+                None => {
                     self.eof = true;
-                    Node::Halt
+                    span.end = pos;
+                    NodeKind::Halt
                 }
-                Token::LeftLoop => {
-                    let mut v = Vec::new();
-                    while let Some(n) = self.get_node() {
-                        if n == Node::Halt {
-                            panic!("Error: Loop syntax error")
-                        }
-                        v.push(n);
-                    }
-                    Node::Loop(v)
+            },
+            Token::LeftLoop => {
+                let mut v = Vec::new();
+                while let Some(n) = self.get_node(Some(pos))? {
+                    v.push(n);
                 }
-                Token::RightLoop => return None,
-            })
-        } else if !self.eof {
-            panic!("Error: Iterator ended before EOF");
-        } else {
-            None
-        }
+                // The closing `]` was the last token read
+                span = span.to(Span::byte(self.last));
+                NodeKind::Loop(v)
+            }
+            Token::RightLoop => match open {
+                Some(_) => return Ok(None),
+                None => return Err(Error::UnmatchedClose(pos)),
+            },
+        };
+        Ok(Some(Node { kind, span }))
     }
 }
 
-impl<T: Iterator<Item = Token>> Iterator for Parser<T> {
-    type Item = Node;
+impl<T: Iterator<Item = Result<(Token, Position)>>> Iterator for Parser<T> {
+    type Item = Result<Node>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.get_node()
+        if self.eof {
+            return None;
+        }
+        let node = self.get_node(None);
+        if node.is_err() {
+            self.eof = true;
+        }
+        node.transpose()
     }
 }
@@ -0,0 +1,174 @@
+// Execution statistics gathered while the VM runs
+
+use super::common::{Instruction, Program, Span};
+
+use std::cmp::Reverse;
+use std::io::{self, Write};
+use std::time::Duration;
+
+/// Hooks the VM calls as it runs. Every method does nothing by default, so
+/// `()`, the observer used unless another is given, compiles away entirely.
+pub trait Observer {
+    /// Instruction `pc` has run. One that fails is reported once it succeeds.
+    #[inline(always)]
+    fn instruction(&mut self, _pc: usize, _instr: Instruction) {}
+
+    /// Cell `idx` was just used, as the pointer's cell or as the far end of
+    /// a fused instruction
+    #[inline(always)]
+    fn touch(&mut self, _idx: usize) {}
+}
+
+impl Observer for () {}
+
+/// Counts how often each instruction runs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    // Runs of each instruction, by pc
+    hits: Vec<u64>,
+    max_cell: usize,
+}
+
+/// How often one loop's body ran
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopProfile {
+    /// Index of the loop's `Jez`
+    pub start: usize,
+    /// Source range from `[` to `]`, if known
+    pub span: Option<Span>,
+    /// Runs of the loop's `Jnz`, one per pass through the body
+    pub iterations: u64,
+}
+
+impl Observer for Profile {
+    #[inline(always)]
+    fn instruction(&mut self, pc: usize, _instr: Instruction) {
+        self.hits[pc] += 1;
+    }
+
+    #[inline(always)]
+    fn touch(&mut self, idx: usize) {
+        if idx > self.max_cell {
+            self.max_cell = idx
+        }
+    }
+}
+
+impl Profile {
+    pub fn new(program: &Program) -> Self {
+        Self {
+            hits: vec![0; program.instructions.len()],
+            max_cell: 0,
+        }
+    }
+
+    /// Instructions run, counting each fused instruction once
+    pub fn steps(&self) -> u64 {
+        self.hits.iter().sum()
+    }
+
+    /// Highest cell index used. Growing the tape on the left shifts indices,
+    /// so this is as it was numbered at the time.
+    pub fn max_cell(&self) -> usize {
+        self.max_cell
+    }
+
+    /// Runs of instruction `pc`
+    pub fn hits(&self, pc: usize) -> u64 {
+        self.hits.get(pc).copied().unwrap_or(0)
+    }
+
+    /// Runs of each kind of instruction that ran at all, most frequent first
+    pub fn counts(&self, program: &Program) -> Vec<(&'static str, u64)> {
+        let mut counts: Vec<(&'static str, u64)> = Vec::new();
+        for (instr, &hits) in program.instructions.iter().zip(&self.hits) {
+            match counts.iter_mut().find(|(name, _)| *name == instr.name()) {
+                Some((_, n)) => *n += hits,
+                None => counts.push((instr.name(), hits)),
+            }
+        }
+        counts.retain(|&(_, n)| n > 0);
+        counts.sort_by_key(|&(_, n)| Reverse(n));
+        counts
+    }
+
+    /// The `n` loops whose bodies ran the most, most first
+    pub fn hottest_loops(&self, program: &Program, n: usize) -> Vec<LoopProfile> {
+        let mut loops: Vec<LoopProfile> = program
+            .instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(pc, instr)| match *instr {
+                Instruction::Jnz(d) if self.hits(pc) > 0 => {
+                    let start = pc - d as usize;
+                    Some(LoopProfile {
+                        start,
+                        span: program.span(start).and_then(|s| program.span(pc).map(|e| s.to(e))),
+                        iterations: self.hits(pc),
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+        loops.sort_by_key(|l| Reverse(l.iterations));
+        loops.truncate(n);
+        loops
+    }
+
+    /// Writes a human-readable report, `elapsed` being how long the run took
+    pub fn write_text<W: Write>(&self, program: &Program, elapsed: Duration, out: &mut W) -> io::Result<()> {
+        writeln!(
+            out,
+            "{} steps in {:.3}s, highest cell {}",
+            self.steps(),
+            elapsed.as_secs_f64(),
+            self.max_cell
+        )?;
+        writeln!(out)?;
+        writeln!(out, "{:<16}{:>16}", "instruction", "count")?;
+        for (name, n) in self.counts(program) {
+            writeln!(out, "{:<16}{:>16}", name, n)?;
+        }
+        let loops = self.hottest_loops(program, 10);
+        if !loops.is_empty() {
+            writeln!(out)?;
+            writeln!(out, "{:<16}{:>16}", "hottest loops", "iterations")?;
+            for l in loops {
+                match l.span {
+                    Some(span) => writeln!(out, "{:<16}{:>16}", span.to_string(), l.iterations)?,
+                    None => writeln!(out, "{:<16}{:>16}", format!("#{}", l.start), l.iterations)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the report as a single JSON object
+    pub fn write_json<W: Write>(&self, program: &Program, elapsed: Duration, out: &mut W) -> io::Result<()> {
+        write!(
+            out,
+            "{{\"steps\":{},\"wall_time_secs\":{},\"max_cell\":{},\"counts\":{{",
+            self.steps(),
+            elapsed.as_secs_f64(),
+            self.max_cell
+        )?;
+        for (i, (name, n)) in self.counts(program).into_iter().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            write!(out, "{}\"{}\":{}", sep, name, n)?;
+        }
+        write!(out, "}},\"hot_loops\":[")?;
+        for (i, l) in self.hottest_loops(program, 10).into_iter().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            let (start, end) = match l.span {
+                Some(span) => (format!("\"{}\"", span.start), format!("\"{}\"", span.end)),
+                None => ("null".to_string(), "null".to_string()),
+            };
+            write!(
+                out,
+                "{}{{\"instruction\":{},\"start\":{},\"end\":{},\"iterations\":{}}}",
+                sep, l.start, start, end, l.iterations
+            )?;
+        }
+        writeln!(out, "]}}")
+    }
+}
@@ -0,0 +1,141 @@
+// Saved interpreter state, for checkpointing runs and reproducing failures
+
+use super::binary::{self, Decoder};
+use super::cell::Cell;
+use super::config::Config;
+use super::error::{Error, Result};
+use super::limits::{Limits, Usage};
+
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 6] = b"BFSNAP";
+const VERSION: u8 = 1;
+
+/// Everything an `Interpreter` needs to carry on where it stopped, taken with
+/// `Interpreter::snapshot` and resumed with `Interpreter::restore`
+///
+/// The file format is the magic bytes `BFSNAP`, a version byte, then
+/// little-endian fields: cell width in bits, the program's fingerprint, the
+/// config, pc, pointer, how far the tape has grown on the left, the usage
+/// counted against limits, and finally the tape length and its cells.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot<C: Cell> {
+    pub(crate) fingerprint: u64,
+    pub(crate) config: Config,
+    pub(crate) pc: usize,
+    pub(crate) ptr: usize,
+    pub(crate) origin: usize,
+    pub(crate) usage: Usage,
+    pub(crate) range: (isize, isize),
+    pub(crate) tape: Vec<C>,
+}
+
+impl<C: Cell> Snapshot<C> {
+    /// `Program::fingerprint` of the program that was running
+    pub fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn ptr(&self) -> usize {
+        self.ptr
+    }
+
+    pub fn tape(&self) -> &[C] {
+        &self.tape
+    }
+
+    pub fn usage(&self) -> Usage {
+        self.usage
+    }
+
+    /// Replaces the limits the restored run is held to. What the run has used
+    /// so far still counts towards them, and `Interpreter::restore` refuses
+    /// the snapshot if that is already more than they allow.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.config.limits = limits;
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(128 + self.tape.len() * C::WIDTH.bits() as usize / 8);
+        bytes.extend_from_slice(MAGIC);
+        binary::put_u8(&mut bytes, VERSION);
+        binary::put_u8(&mut bytes, C::WIDTH.bits() as u8);
+        binary::put_u64(&mut bytes, self.fingerprint);
+        binary::put_config(&mut bytes, &self.config);
+        binary::put_u64(&mut bytes, self.pc as u64);
+        binary::put_u64(&mut bytes, self.ptr as u64);
+        binary::put_u64(&mut bytes, self.origin as u64);
+        binary::put_u64(&mut bytes, self.usage.steps);
+        binary::put_u64(&mut bytes, self.usage.output);
+        binary::put_u64(&mut bytes, self.usage.cells as u64);
+        binary::put_i64(&mut bytes, self.range.0 as i64);
+        binary::put_i64(&mut bytes, self.range.1 as i64);
+        binary::put_u64(&mut bytes, self.tape.len() as u64);
+        let size = C::WIDTH.bits() as usize / 8;
+        for cell in &self.tape {
+            bytes.extend_from_slice(&cell.to_u64().to_le_bytes()[..size]);
+        }
+        out.write_all(&bytes)
+    }
+
+    /// Reads a snapshot written by `write_to` for cells of type `C`
+    pub fn read_from<R: Read>(input: &mut R) -> Result<Self> {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes).map_err(|e| Error::Io(e, None))?;
+        let mut d = Decoder::new(&bytes, "snapshot");
+        if d.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(d.error("not a snapshot file"));
+        }
+        if d.u8()? != VERSION {
+            return Err(d.error("unsupported version"));
+        }
+        let bits = d.u8()?;
+        if bits as u32 != C::WIDTH.bits() {
+            return Err(d.error(&format!("saved with {}-bit cells, not {}", bits, C::WIDTH)));
+        }
+        let fingerprint = d.u64()?;
+        let config = d.config()?;
+        let pc = d.usize()?;
+        let ptr = d.usize()?;
+        let origin = d.usize()?;
+        let usage = Usage {
+            steps: d.u64()?,
+            output: d.u64()?,
+            cells: d.usize()?,
+        };
+        let range = (d.i64()? as isize, d.i64()? as isize);
+        let len = d.usize()?;
+        let size = C::WIDTH.bits() as usize / 8;
+        let cells = d.bytes(len.checked_mul(size).ok_or_else(|| d.error("tape too large"))?)?;
+        let tape = cells
+            .chunks(size)
+            .map(|c| {
+                let mut b = [0; 8];
+                b[..size].copy_from_slice(c);
+                C::from_i64(u64::from_le_bytes(b) as i64)
+            })
+            .collect::<Vec<_>>();
+        d.finish()?;
+        if ptr >= tape.len() || origin > tape.len() {
+            return Err(d.error("pointer outside the tape"));
+        }
+        Ok(Self {
+            fingerprint,
+            config,
+            pc,
+            ptr,
+            origin,
+            usage,
+            range,
+            tape,
+        })
+    }
+}
@@ -0,0 +1,126 @@
+// The data tape and pointer, shared by both interpreters
+
+use super::cell::Cell;
+use super::config::TapePolicy;
+use super::error::{Error, Result};
+
+use std::cmp;
+
+pub(crate) struct Tape<C: Cell> {
+    cells: Vec<C>,
+    ptr: usize,
+    policy: TapePolicy,
+    // Cells added on the left by growing
+    origin: usize,
+}
+
+impl<C: Cell> Tape<C> {
+    pub fn new(size: usize, policy: TapePolicy) -> Self {
+        Self {
+            // An empty tape has nowhere to put the pointer
+            cells: vec![C::default(); cmp::max(size, 1)],
+            ptr: 0,
+            policy,
+            origin: 0,
+        }
+    }
+
+    /// A tape holding `cells`, as saved from another tape
+    pub fn from_parts(cells: Vec<C>, ptr: usize, origin: usize, policy: TapePolicy) -> Self {
+        debug_assert!(ptr < cells.len());
+        Self {
+            cells,
+            ptr,
+            policy,
+            origin,
+        }
+    }
+
+    #[inline]
+    pub fn ptr(&self) -> usize {
+        self.ptr
+    }
+
+    /// Index of the cell that was first when the tape was created
+    #[inline]
+    pub fn origin(&self) -> usize {
+        self.origin
+    }
+
+    pub fn cells(&self) -> &[C] {
+        &self.cells
+    }
+
+    #[inline]
+    pub fn get(&self) -> C {
+        self.cells[self.ptr]
+    }
+
+    #[inline]
+    pub fn set(&mut self, value: C) {
+        self.cells[self.ptr] = value
+    }
+
+    #[inline]
+    pub fn cell_mut(&mut self, idx: usize) -> &mut C {
+        &mut self.cells[idx]
+    }
+
+    /// Index of the cell `delta` away from the pointer, growing the tape if the
+    /// policy allows. Growing on the left shifts every index, the pointer's too.
+    #[inline]
+    pub fn index(&mut self, delta: isize) -> Result<usize> {
+        let target = self.ptr.wrapping_add(delta as usize);
+        if target < self.cells.len() {
+            Ok(target)
+        } else {
+            self.index_outside(delta)
+        }
+    }
+
+    #[cold]
+    fn index_outside(&mut self, delta: isize) -> Result<usize> {
+        let len = self.cells.len();
+        let target = self.ptr as isize + delta;
+        match self.policy {
+            TapePolicy::Wrap => Ok(target.rem_euclid(len as isize) as usize),
+            TapePolicy::Error => Err(Error::TapeOutOfBounds(None)),
+            TapePolicy::GrowRight | TapePolicy::Grow if target >= 0 => {
+                let target = target as usize;
+                self.cells.resize(cmp::max(target + 1, len * 2), C::default());
+                Ok(target)
+            }
+            TapePolicy::GrowRight => Err(Error::TapeOutOfBounds(None)),
+            TapePolicy::Grow => {
+                let extra = cmp::max(target.unsigned_abs(), len);
+                let mut cells = vec![C::default(); extra];
+                cells.extend_from_slice(&self.cells);
+                self.cells = cells;
+                self.ptr += extra;
+                self.origin += extra;
+                Ok((target + extra as isize) as usize)
+            }
+        }
+    }
+
+    /// Moves the pointer to `idx`, which must be on the tape
+    #[inline]
+    pub fn set_ptr(&mut self, idx: usize) {
+        debug_assert!(idx < self.cells.len());
+        self.ptr = idx
+    }
+
+    #[inline]
+    pub fn move_by(&mut self, delta: isize) -> Result<()> {
+        self.ptr = self.index(delta)?;
+        Ok(())
+    }
+}
+
+// Raw access for the JIT, which keeps the pointer in a register
+#[cfg_attr(not(all(feature = "jit", target_arch = "x86_64", unix)), allow(dead_code))]
+impl<C: Cell> Tape<C> {
+    pub fn cells_mut(&mut self) -> &mut [C] {
+        &mut self.cells
+    }
+}
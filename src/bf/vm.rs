@@ -1,183 +1,455 @@
-use super::common::Instruction;
+use super::binary;
+use super::cell::Cell;
+use super::common::{Instruction, Position, Program, Span};
+use super::config::{Config, EofPolicy};
+use super::error::{Error, Result};
+use super::limits::{Meter, Usage};
+use super::profile::Observer;
+use super::snapshot::Snapshot;
+use super::tape::Tape;
 use Instruction::*;
 
-use std::io::{Read, Write};
-
-use std::result::Result;
-
-use std::collections::HashMap;
-
-#[derive(Default, Debug)]
-struct Profile {
-    add: usize,
-    jez: usize,
-    jnz: usize,
-    left: usize,
-    right: usize,
-    search_left: usize,
-    search_right: usize,
-    add_move_left: usize,
-    add_move_right: usize,
-    zero_right: usize,
-    zero_left: usize,
-    zero: usize,
-    write: usize,
-    read: usize,
-    halt: usize 
+use std::io::{ErrorKind, Read, Write};
+
+/// Where a resumable run stopped
+#[derive(Debug)]
+pub enum Status {
+    /// The program ran to its end
+    Halted,
+    /// A `,` found the input stream returning `ErrorKind::WouldBlock`. The pc
+    /// stays on the `,` so it reads again when resumed.
+    NeedsInput,
+    /// The run stopped where the caller asked
+    Paused,
+    Error(Error),
 }
 
-pub struct Interpreter<'a, T: Read, U: Write> {
+pub struct Interpreter<'a, C: Cell, T: Read, U: Write, O: Observer = ()> {
     read_stream: &'a mut T,
     write_stream: &'a mut U,
     instructions: &'a [Instruction],
+    spans: &'a [Span],
     pc: usize,
-    memory: Vec<u8>,
-    memory_size: usize,
-    mem_idx: usize,
+    tape: Tape<C>,
+    eof_policy: EofPolicy,
+    // Kept whole for snapshots
+    config: Config,
+    meter: Meter,
+    // Whether the config sets any limits; without them nothing is counted
+    metered: bool,
+    observer: O,
 }
 
-impl<'a, T: Read, U: Write> Interpreter<'a, T, U> {
-    pub fn new(src: &'a [Instruction], input: &'a mut T, output: &'a mut U) -> Self {
+impl<'a, T: Read, U: Write> Interpreter<'a, u8, T, U> {
+    pub fn new(src: &'a Program, input: &'a mut T, output: &'a mut U) -> Self {
         Self::with_capacity(src, input, output, 30000)
     }
 
     pub fn with_capacity(
-        instructions: &'a [Instruction],
+        program: &'a Program,
         input: &'a mut T,
         output: &'a mut U,
         capacity: usize,
+    ) -> Self {
+        Self::with_config(program, input, output, Config::new().tape_size(capacity))
+    }
+}
+
+impl<'a, C: Cell, T: Read, U: Write> Interpreter<'a, C, T, U> {
+    pub fn with_config(
+        program: &'a Program,
+        input: &'a mut T,
+        output: &'a mut U,
+        config: Config,
+    ) -> Self {
+        Self::with_observer(program, input, output, config, ())
+    }
+}
+
+impl<'a, C: Cell, T: Read, U: Write, O: Observer> Interpreter<'a, C, T, U, O> {
+    /// Like `with_config`, calling `observer` as the program runs
+    pub fn with_observer(
+        program: &'a Program,
+        input: &'a mut T,
+        output: &'a mut U,
+        config: Config,
+        observer: O,
     ) -> Self {
         Self {
             read_stream: input,
             write_stream: output,
-            instructions,
+            instructions: &program.instructions,
+            spans: &program.spans,
             pc: 0,
-            memory: vec![0; capacity],
-            memory_size: capacity,
-            mem_idx: 0,
+            tape: Tape::new(config.tape_size, config.tape_policy),
+            eof_policy: config.eof_policy,
+            meter: Meter::new(config.limits),
+            metered: !config.limits.is_unlimited(),
+            config,
+            observer,
         }
     }
 
-    pub fn run(&mut self) -> Result<(), &'static str> {
-        // let mut profile: Profile = Default::default();
-        // let mut total: usize = 0;
-        // let mut subroutines: HashMap<&'a[Instruction], usize> = HashMap::new();
-        loop {
-            let instruction = &self.instructions[self.pc];
-            // println!("inst:{:?}, pc:{}, ptr:{}", instruction, self.pc, self.mem_idx);
-            match *instruction {
-                Mandel(x, n) => {
-                    while self.memory[self.mem_idx] != 0 {
-                        self.memory[self.mem_idx + x as usize + n as usize] = self.memory[self.mem_idx + x as usize + n as usize].wrapping_add(self.memory[self.mem_idx + x as usize]);
-                        self.memory[self.mem_idx + x as usize] = 0;
-                        self.mem_idx = (self.mem_idx - n as usize) % self.memory_size;
-                    }
-                },
-                ZeroRight(n) => {
-                    // profile.zero_right += 1;
-                    let target = (self.mem_idx + n as usize) % self.memory_size;
-                    while self.mem_idx != target {
-                        self.memory[self.mem_idx] = 0;
-                        self.mem_idx = (self.mem_idx + 1 as usize) % self.memory_size;
-                    }
-                },
-                ZeroLeft(n) => {
-                    // profile.zero_left += 1;
-                    let target = (self.mem_idx - n as usize) % self.memory_size;
-                    while self.mem_idx != target {
-                        self.memory[self.mem_idx] = 0;
-                        self.mem_idx = (self.mem_idx - 1 as usize) % self.memory_size;
-                    }
-                },
-                AddMoveRight(n) => {
-                    // profile.add_move_right += 1;
-                    let new_idx = (self.mem_idx + n as usize) % self.memory_size;
-                    self.memory[new_idx] = self.memory[new_idx].wrapping_add(self.memory[self.mem_idx]);
-                    self.memory[self.mem_idx] = 0;
-                },
-                AddMoveLeft(n) => {
-                    // profile.add_move_left += 1;
-                    let new_idx = (self.mem_idx - n as usize) % self.memory_size;
-                    self.memory[new_idx] = self.memory[new_idx].wrapping_add(self.memory[self.mem_idx]);
-                    self.memory[self.mem_idx] = 0;
-
-                },
-                SearchLeft(num) => {
-                    // profile.search_left += 1;
-                    while self.memory[self.mem_idx] != 0 {
-                        self.mem_idx = (self.mem_idx - num as usize) % self.memory_size
-                    }
-                },
-                SearchRight(num) => {
-                    // profile.search_right += 1;
-                    while self.memory[self.mem_idx] != 0 {
-                        self.mem_idx = (self.mem_idx + num as usize) % self.memory_size
-                    }
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    pub fn into_observer(self) -> O {
+        self.observer
+    }
+
+    /// How much of each limit in the config the run has used so far. Usage is
+    /// only counted when the config sets at least one limit.
+    pub fn usage(&self) -> Usage {
+        self.meter.usage()
+    }
+
+    /// The state of the run, to save and carry on later with `restore`
+    pub fn snapshot(&self) -> Snapshot<C> {
+        Snapshot {
+            fingerprint: binary::fingerprint(self.instructions),
+            config: self.config.clone(),
+            pc: self.pc,
+            ptr: self.tape.ptr(),
+            origin: self.tape.origin(),
+            usage: self.meter.usage(),
+            range: self.meter.range(),
+            tape: self.tape.cells().to_vec(),
+        }
+    }
+
+    /// Replaces the state of the run, config included, with a snapshot of an
+    /// interpreter running the same program. A snapshot that has already used
+    /// more than its limits allow is refused with `Error::LimitExceeded`.
+    pub fn restore(&mut self, snapshot: Snapshot<C>) -> Result<()> {
+        if snapshot.fingerprint != binary::fingerprint(self.instructions) || snapshot.pc >= self.instructions.len() {
+            return Err(Error::Format("snapshot: taken from a different program".to_string()));
+        }
+        if let Some(limit) = snapshot.config.limits.passed_by(snapshot.usage) {
+            return Err(Error::LimitExceeded(limit, None));
+        }
+        let config = snapshot.config;
+        self.pc = snapshot.pc;
+        self.tape = Tape::from_parts(snapshot.tape, snapshot.ptr, snapshot.origin, config.tape_policy);
+        self.eof_policy = config.eof_policy;
+        self.meter = Meter::resume(config.limits, snapshot.usage, snapshot.range);
+        self.metered = !config.limits.is_unlimited();
+        self.config = config;
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn touch<const METERED: bool>(&mut self, idx: usize) -> Result<()> {
+        self.observer.touch(idx);
+        if METERED {
+            self.meter.touch(idx, self.tape.origin())?
+        }
+        Ok(())
+    }
+
+    // Index of the cell `offset` from the pointer. Other cells than the
+    // current one count as touched.
+    #[inline(always)]
+    fn at<const METERED: bool>(&mut self, offset: i32) -> Result<usize> {
+        if offset == 0 {
+            return Ok(self.tape.ptr());
+        }
+        let idx = self.tape.index(offset as isize)?;
+        self.touch::<METERED>(idx)?;
+        Ok(idx)
+    }
+
+    // Moves the pointer, checking the limits before anything changes so that
+    // a run stopped here can resume by running the instruction again
+    #[inline(always)]
+    fn move_by<const METERED: bool>(&mut self, delta: isize) -> Result<()> {
+        let dst = self.tape.index(delta)?;
+        self.touch::<METERED>(dst)?;
+        self.tape.set_ptr(dst);
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn count<const METERED: bool>(&mut self) -> Result<()> {
+        if METERED {
+            self.meter.step()?
+        }
+        Ok(())
+    }
+
+    // Adds the current cell to the one `delta` away and zeroes it, like `[->+<]`
+    #[inline]
+    fn add_move<const METERED: bool>(&mut self, delta: isize) -> Result<()> {
+        // The loop this replaces never touches the target when the cell is 0
+        let v = self.tape.get();
+        if !v.is_zero() {
+            let dst = self.tape.index(delta)?;
+            self.touch::<METERED>(dst)?;
+            let cell = self.tape.cell_mut(dst);
+            *cell = cell.wrapping_add(v);
+            self.tape.set(C::default());
+        }
+        Ok(())
+    }
+
+    /// Index of the next instruction to run
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Index of the cell under the data pointer
+    pub fn ptr(&self) -> usize {
+        self.tape.ptr()
+    }
+
+    pub fn tape(&self) -> &[C] {
+        self.tape.cells()
+    }
+
+    /// The input stream, for example to queue more input after `NeedsInput`
+    pub fn input_mut(&mut self) -> &mut T {
+        self.read_stream
+    }
+
+    pub fn output_mut(&mut self) -> &mut U {
+        self.write_stream
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        let result = self.execute();
+        self.at_pc(result)
+    }
+
+    /// Runs at most `n` instructions, counting each fused instruction once
+    pub fn step(&mut self, n: u64) -> Status {
+        if n == 0 {
+            return Status::Paused;
+        }
+        let mut left = n;
+        self.run_until(|_| {
+            left -= 1;
+            left == 0
+        })
+    }
+
+    /// Runs until the program stops or `pause`, called after each instruction,
+    /// returns true. Every status but `Halted` can be resumed with another call;
+    /// after an `Error` the failing instruction runs again, counted and seen by
+    /// the observer only once it succeeds.
+    pub fn run_until<F: FnMut(&Self) -> bool>(&mut self, mut pause: F) -> Status {
+        let result = if self.metered {
+            self.execute_until::<true, F>(&mut pause)
+        } else {
+            self.execute_until::<false, F>(&mut pause)
+        };
+        match self.at_pc(result) {
+            Ok(true) => Status::Paused,
+            Ok(false) => Status::Halted,
+            Err(Error::Io(ref e, _)) if e.kind() == ErrorKind::WouldBlock && self.instructions[self.pc] == Read => {
+                Status::NeedsInput
+            }
+            Err(e) => Status::Error(e),
+        }
+    }
+
+    // A failing instruction never advances the pc
+    fn at_pc<R>(&self, result: Result<R>) -> Result<R> {
+        result.map_err(|e| {
+            let pos = self.spans.get(self.pc).map(|&span| failed_at(self.instructions[self.pc], span, &self.tape, &e));
+            e.or_at(pos)
+        })
+    }
+
+    // Whether `pause` stopped the run before it halted
+    fn execute_until<const METERED: bool, F: FnMut(&Self) -> bool>(&mut self, pause: &mut F) -> Result<bool> {
+        while self.execute_one::<METERED>()? {
+            if pause(self) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn execute(&mut self) -> Result<()> {
+        if self.metered {
+            while self.execute_one::<true>()? {}
+        } else {
+            while self.execute_one::<false>()? {}
+        }
+        Ok(())
+    }
+
+    // An instruction that fails leaves the pc where it is and is run again on
+    // resuming, so what it counted is taken back, except for the passes a
+    // Mandel or search finished, which are not repeated
+    #[inline(always)]
+    fn execute_one<const METERED: bool>(&mut self) -> Result<bool> {
+        let (pc, instruction) = (self.pc, self.instructions[self.pc]);
+        let mark = self.meter.usage();
+        let mut passes = 0;
+        match self.dispatch::<METERED>(instruction, &mut passes) {
+            Ok(more) => {
+                self.observer.instruction(pc, instruction);
+                Ok(more)
+            }
+            Err(e) => {
+                if METERED {
+                    self.meter.rewind(Usage { steps: mark.steps + passes, ..mark });
                 }
-                Zero => {
-                    // profile.zero += 1;
-                    self.memory[self.mem_idx] = 0
-                },
-                Add(amt) => {
-                    // profile.add += 1;
-                    self.memory[self.mem_idx] = self.memory[self.mem_idx].wrapping_add(amt)
-                },
-                Jez(dst) => {
-                    // profile.jez += 1;
-                    if self.memory[self.mem_idx] == 0 {
-                        self.pc += dst as usize
+                Err(e)
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn dispatch<const METERED: bool>(&mut self, instruction: Instruction, passes: &mut u64) -> Result<bool> {
+        self.count::<METERED>()?;
+        match instruction {
+            Mandel(x, n) => {
+                while !self.tape.get().is_zero() {
+                    self.count::<METERED>()?;
+                    let src = self.tape.index(x as isize)?;
+                    self.touch::<METERED>(src)?;
+                    let v = *self.tape.cell_mut(src);
+                    if !v.is_zero() {
+                        let dst = self.tape.index(x as isize + n as isize)?;
+                        self.touch::<METERED>(dst)?;
+                        let cell = self.tape.cell_mut(dst);
+                        *cell = cell.wrapping_add(v);
+                        *self.tape.cell_mut(src) = C::default();
                     }
+                    self.move_by::<METERED>(-(n as isize))?;
+                    *passes += 1;
                 }
-                Jnz(dst) => {
-                    // *subroutines.entry(&self.instructions[self.pc - dst as usize..=self.pc]).or_insert(0) += 1;
-                    // profile.jnz += 1;
-                    if self.memory[self.mem_idx] != 0 {
-                        self.pc -= dst as usize
-                    }
+            },
+            ZeroRight(n) => {
+                // Charged up front, as stopping halfway could not be resumed
+                if METERED {
+                    self.meter.steps(n as u64)?;
+                }
+                let end = self.tape.index(n as isize)?;
+                self.touch::<METERED>(end)?;
+                for _ in 0..n {
+                    self.tape.set(C::default());
+                    self.tape.move_by(1)?;
+                }
+            },
+            ZeroLeft(n) => {
+                // As for ZeroRight
+                if METERED {
+                    self.meter.steps(n as u64)?;
+                }
+                let end = self.tape.index(-(n as isize))?;
+                self.touch::<METERED>(end)?;
+                for _ in 0..n {
+                    self.tape.set(C::default());
+                    self.tape.move_by(-1)?;
+                }
+            },
+            AddMoveRight(n) => {
+                self.add_move::<METERED>(n as isize)?
+            },
+            AddMoveLeft(n) => {
+                self.add_move::<METERED>(-(n as isize))?
+            },
+            MulAdd(offset, factor) => {
+                // As with AddMove, the loop this came from never touches the
+                // target when the cell is 0
+                let v = self.tape.get();
+                if !v.is_zero() {
+                    let dst = self.tape.index(offset as isize)?;
+                    self.touch::<METERED>(dst)?;
+                    let cell = self.tape.cell_mut(dst);
+                    *cell = cell.wrapping_add(v.wrapping_mul(C::from_i64(factor)));
+                }
+            },
+            SearchLeft(num) => {
+                while !self.tape.get().is_zero() {
+                    self.count::<METERED>()?;
+                    self.move_by::<METERED>(-(num as isize))?;
+                    *passes += 1;
                 }
-                Right(amt) => {
-                    // profile.right += 1;
-                    self.mem_idx = (self.mem_idx + amt as usize) % self.memory_size
-                },
-                Left(amt) => {
-                    // profile.left += 1;
-                    self.mem_idx = (self.mem_idx - amt as usize) % self.memory_size
-                },
-                Write => match self
+            },
+            SearchRight(num) => {
+                while !self.tape.get().is_zero() {
+                    self.count::<METERED>()?;
+                    self.move_by::<METERED>(num as isize)?;
+                    *passes += 1;
+                }
+            }
+            Zero => {
+                self.tape.set(C::default())
+            },
+            Add(offset, amt) => {
+                let idx = self.at::<METERED>(offset)?;
+                let cell = self.tape.cell_mut(idx);
+                *cell = cell.wrapping_add(C::from_i64(amt))
+            },
+            Set(offset, v) => {
+                let idx = self.at::<METERED>(offset)?;
+                *self.tape.cell_mut(idx) = C::from_i64(v)
+            },
+            Jez(dst) => {
+                if self.tape.get().is_zero() {
+                    self.pc += dst as usize
+                }
+            }
+            Jnz(dst) => {
+                if !self.tape.get().is_zero() {
+                    self.pc -= dst as usize
+                }
+            }
+            Right(amt) => {
+                self.move_by::<METERED>(amt as isize)?
+            },
+            Left(amt) => {
+                self.move_by::<METERED>(-(amt as isize))?
+            },
+            Write(offset) => {
+                let idx = self.at::<METERED>(offset)?;
+                if METERED {
+                    self.meter.output()?;
+                }
+                match self
                     .write_stream
-                    .write(&self.memory[self.mem_idx..self.mem_idx + 1])
-                {
-                    Ok(_) => {
-                        // profile.write += 1
-                    }
-                    Err(_) => return Err("Failed to write to output."),
-                },
-                Read => match self
-                    .read_stream
-                    .read(&mut self.memory[self.mem_idx..self.mem_idx + 1])
+                    .write_all(&[self.tape.cells()[idx].to_byte()])
                 {
-                    Ok(_) => {
-                        // profile.read += 1
-                    }
-                    Err(_) => return Err("Failed to read from input."),
-                },
-                Halt => {
-                    /*
-                    profile.halt += 1;
-                    println!("{:#?}", profile);
-                    let mut loops = subroutines.into_iter().collect::<Vec<(&[Instruction], usize)>>();
-                    loops.sort_by(|x, y| x.1.cmp(&y.1));
-                    for e in loops {
-                        println!("{} -> {:?}", e.1, e.0);
-                    }
-                    println!("{} instructions executed.", total);
-                    println!("{:?}", &self.memory[0..30]);
-                    */
-                    return Ok(())
-                },
+                    Ok(_) => {}
+                    Err(e) => return Err(Error::Io(e, None)),
+                }
             }
-            // total += 1;
-            self.pc += 1;
+            Read => {
+                let mut b = [0u8; 1];
+                match self.read_stream.read(&mut b) {
+                    Ok(0) => self.tape.set(self.eof_policy.apply(self.tape.get())),
+                    Ok(_) => self.tape.set(C::from_byte(b[0])),
+                    Err(e) => return Err(Error::Io(e, None)),
+                }
+            }
+            Halt => {
+                return Ok(false)
+            },
         }
+        self.pc += 1;
+        Ok(true)
+    }
+}
+
+// Where `instr`, covering `span`, failed with `e` on `tape`: the start of its
+// source, but for a move off the tape written as that many commands in a row,
+// the command that left it
+pub(crate) fn failed_at<C: Cell>(instr: Instruction, span: Span, tape: &Tape<C>, e: &Error) -> Position {
+    let (n, k) = match (instr, e) {
+        (Right(n), Error::TapeOutOfBounds(None)) => (n, tape.cells().len() - 1 - tape.ptr()),
+        (Left(n), Error::TapeOutOfBounds(None)) => (n, tape.ptr()),
+        _ => return span.start,
+    };
+    if span.start.line != span.end.line || span.end.offset - span.start.offset != n as usize {
+        return span.start;
+    }
+    Position {
+        offset: span.start.offset + k,
+        column: span.start.column + k,
+        ..span.start
     }
 }
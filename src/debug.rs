@@ -0,0 +1,219 @@
+// `bfinterp debug`: a line-oriented front end for bf::Debugger
+
+use bfinterp::bf::{self, Cell, Debugger, Stop};
+
+use std::io::{self, BufRead, Read, Write};
+
+const HELP: &str = "\
+Commands:
+  s, step [N]       Run N instructions [default: 1]
+  n, next           Run the loop starting here to completion, or step
+  c, continue       Run until a breakpoint, a watchpoint or the end
+  b, break L:C      Break at the first instruction at or after line L, column C
+  b, break          List breakpoints
+  d, delete N       Remove the breakpoint on instruction N
+  w, watch CELL     Stop when an instruction changes CELL
+  unwatch CELL      Remove a watchpoint
+  t, tape [CELL]    Show the tape around CELL [default: the pointer]
+  i, info           Show the current instruction and tape
+  h, help           Print this message
+  q, quit           Leave the debugger
+An empty line repeats the last command. A `#` in the source sets a breakpoint.";
+
+// Cells shown on either side of the centre of the tape view
+const TAPE_RADIUS: usize = 8;
+
+struct Session<'a, C: Cell, T: Read, U: Write> {
+    dbg: Debugger<'a, C, T, U>,
+    lines: Vec<&'a [u8]>,
+}
+
+/// Runs an interactive session on stdin and stdout until `quit` or end of input
+pub fn run<C: Cell, T: Read, U: Write>(
+    program: &bf::Program,
+    source: &[u8],
+    vm: bf::Interpreter<C, T, U>,
+) -> io::Result<()> {
+    let mut session = Session {
+        dbg: Debugger::new(vm, program),
+        lines: source.split(|&b| b == b'\n').collect(),
+    };
+    let hashes = session.dbg.break_on_hashes(source).len();
+    println!(
+        "{} instructions, {} breakpoints from `#`. Type `help` for commands.",
+        program.instructions.len(),
+        hashes
+    );
+    session.info();
+
+    let stdin = io::stdin();
+    let mut last = String::new();
+    loop {
+        print!("(bfdb) ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(());
+        }
+        let line = match line.trim() {
+            "" => last.clone(),
+            l => l.to_string(),
+        };
+        let mut words = line.split_whitespace();
+        let cmd = match words.next() {
+            Some(cmd) => cmd,
+            None => continue,
+        };
+        let arg = words.next();
+        match (cmd, arg) {
+            ("q", _) | ("quit", _) => return Ok(()),
+            ("h", _) | ("help", _) => println!("{}", HELP),
+            ("s", _) | ("step", _) => match arg.map(str::parse).unwrap_or(Ok(1)) {
+                Ok(n) => {
+                    let mut stop = Stop::Step;
+                    for _ in 0..n {
+                        stop = session.dbg.step();
+                        if !matches!(stop, Stop::Step) {
+                            break;
+                        }
+                    }
+                    session.stopped(stop)
+                }
+                Err(_) => println!("invalid count `{}`", arg.unwrap_or_default()),
+            },
+            ("n", _) | ("next", _) => {
+                let stop = session.dbg.step_over();
+                session.stopped(stop)
+            }
+            ("c", _) | ("continue", _) => {
+                let stop = session.dbg.resume();
+                session.stopped(stop)
+            }
+            ("b", None) | ("break", None) => session.list_breakpoints(),
+            ("b", Some(at)) | ("break", Some(at)) => match parse_position(at) {
+                Some((line, column)) => match session.dbg.break_at(line, column) {
+                    Some(idx) => println!("breakpoint on instruction {}", idx),
+                    None => println!("no instruction at or after {}", at),
+                },
+                None => println!("expected LINE:COLUMN, got `{}`", at),
+            },
+            ("d", Some(n)) | ("delete", Some(n)) => match n.parse() {
+                Ok(idx) if session.dbg.remove_breakpoint(idx) => println!("removed breakpoint {}", idx),
+                _ => println!("no breakpoint on instruction `{}`", n),
+            },
+            ("w", Some(n)) | ("watch", Some(n)) => match n.parse() {
+                Ok(cell) if session.dbg.watch(cell) => println!("watching cell {}", cell),
+                _ => println!("no cell `{}` on the tape", n),
+            },
+            ("unwatch", Some(n)) => match n.parse() {
+                Ok(cell) if session.dbg.unwatch(cell) => println!("stopped watching cell {}", cell),
+                _ => println!("cell `{}` is not watched", n),
+            },
+            ("t", _) | ("tape", _) => match arg.map(str::parse) {
+                None => session.tape(session.dbg.interpreter().ptr()),
+                Some(Ok(cell)) => session.tape(cell),
+                Some(Err(_)) => println!("invalid cell `{}`", arg.unwrap_or_default()),
+            },
+            ("i", _) | ("info", _) => session.info(),
+            _ => println!("unknown command `{}`; try `help`", line),
+        }
+        last = line;
+    }
+}
+
+fn parse_position(s: &str) -> Option<(usize, usize)> {
+    let mut parts = s.splitn(2, ':');
+    let line = parts.next()?.parse().ok()?;
+    let column = parts.next().map_or(Ok(1), str::parse).ok()?;
+    Some((line, column))
+}
+
+impl<'a, C: Cell, T: Read, U: Write> Session<'a, C, T, U> {
+    fn stopped(&self, stop: Stop<C>) {
+        let _ = io::stdout().flush();
+        match stop {
+            Stop::Step => {}
+            Stop::Breakpoint(idx) => println!("breakpoint on instruction {}", idx),
+            Stop::Watchpoint { cell, old, new } => println!("cell {} changed: {:?} -> {:?}", cell, old, new),
+            Stop::NeedsInput => println!("waiting for input"),
+            Stop::Halted => println!("program halted"),
+            Stop::Error(e) => println!("program failed: {}", e),
+        }
+        self.info();
+    }
+
+    fn list_breakpoints(&self) {
+        let program = self.dbg.program();
+        let mut any = false;
+        for idx in self.dbg.breakpoints() {
+            any = true;
+            match program.span(idx) {
+                Some(span) => println!("  {:>5}  {}", idx, span),
+                None => println!("  {:>5}", idx),
+            }
+        }
+        if !any {
+            println!("no breakpoints");
+        }
+    }
+
+    // Current instruction, the source it came from and the tape around the pointer
+    fn info(&self) {
+        let vm = self.dbg.interpreter();
+        let program = self.dbg.program();
+        let pc = vm.pc();
+        if let Some(instr) = program.instructions.get(pc) {
+            match program.span(pc) {
+                Some(span) => {
+                    println!("{:>5}  {:?}  at {}", pc, instr, span);
+                    self.snippet(span);
+                }
+                None => println!("{:>5}  {:?}", pc, instr),
+            }
+        }
+        self.tape(vm.ptr());
+    }
+
+    // The first source line of `span`, underlined
+    fn snippet(&self, span: bf::Span) {
+        let text = match self.lines.get(span.start.line - 1) {
+            Some(text) => String::from_utf8_lossy(text),
+            None => return,
+        };
+        let end = if span.end.line == span.start.line {
+            span.end.column
+        } else {
+            text.len() + 1
+        };
+        let gutter = span.start.line.to_string();
+        println!("{} | {}", gutter, text.trim_end());
+        println!(
+            "{:gutter$} | {:indent$}{}",
+            "",
+            "",
+            "^".repeat(end.saturating_sub(span.start.column).max(1)),
+            gutter = gutter.len(),
+            indent = span.start.column - 1
+        );
+    }
+
+    fn tape(&self, centre: usize) {
+        let vm = self.dbg.interpreter();
+        let tape = vm.tape();
+        let start = centre.saturating_sub(TAPE_RADIUS);
+        let end = (centre + TAPE_RADIUS + 1).min(tape.len());
+        let mut line = String::from("tape:");
+        for (idx, v) in tape.iter().enumerate().take(end).skip(start) {
+            if idx == vm.ptr() {
+                line += &format!(" [{}:{:?}]", idx, v);
+            } else {
+                line += &format!(" {}:{:?}", idx, v);
+            }
+        }
+        if end < tape.len() {
+            line += " ...";
+        }
+        println!("{}", line);
+    }
+}
@@ -1,25 +1,572 @@
-mod bf;
+mod debug;
 
+use bfinterp::bf;
+
+use std::env;
 use std::fs::File;
-use std::io::BufReader;
-use std::io::{stdin, stdout};
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use std::process;
+use std::time::Instant;
+
+const USAGE: &str = "\
+Usage: bfinterp [run] [OPTIONS] <PROGRAM>
+       bfinterp [run] [OPTIONS] -e <CODE>
+       bfinterp debug [OPTIONS] <PROGRAM>
+       bfinterp compile [OPTIONS] <PROGRAM> [-o <FILE>]
+       bfinterp disasm [OPTIONS] <PROGRAM>
+       bfinterp fmt [OPTIONS] <PROGRAM> [-o <FILE>]
+
+Commands:
+  run        Run the program; the default
+  debug      Step through the program in the optimizing VM; type `help` at its prompt
+  compile    Save the optimized program as bytecode, by default to PROGRAM with a
+             `.bfc` extension. Cell width and tape settings are saved with it
+  disasm     List the optimized instructions with jump targets and the source of each
+  fmt        Print the source laid out one way: loop bodies indented, runs of a command
+             grouped, lines wrapped. Comments are dropped unless --keep-comments is given
+
+Arguments:
+  <PROGRAM>  Brainfuck source or bytecode file, or `-` to read it from stdin.
+             Bytecode runs with its saved settings unless options override them
+
+Options:
+  -e, --eval <CODE>        Run CODE instead of reading a program file
+  -i, --input <FILE>       Read program input from FILE instead of stdin
+  -t, --tape-size <CELLS>  Number of cells on the tape [default: 30000]
+  -w, --cell-width <BITS>  Cell width: 8, 16, 32 or 64 [default: 8]
+      --tape <POLICY>      Leaving the tape: `wrap`, `error`, `grow-right` or `grow` [default: wrap]
+      --eof <POLICY>       `,` at end of input: `unchanged`, `zero` or `max` (-1) [default: unchanged]
+      --engine <ENGINE>    `opt` (optimizing VM), `old` (token-level VM) or `jit`
+                           (native code, if built with the `jit` feature on x86-64 Unix) [default: opt]
+  -O <LEVEL>               Optimization level: 0 (none), 1 (all but passes made for one
+                           program) or 2 (all); also -O0, -O1 and -O2 [default: 2]
+      --enable-pass <PASS> Apply PASS whatever the level: `zero`, `search`, `linear`,
+                           `mandel`, `offsets` or `zero-runs`. May be repeated
+      --disable-pass <PASS>
+                           Leave PASS out whatever the level. May be repeated
+      --emit <LANG>        Print the program translated to LANG (`c`, `rust`, `wat` or `wasm`) instead of running it,
+                           or `bf` for minified Brainfuck
+      --profile <FORMAT>   After the run, print instruction counts, hot loops and timing to stderr
+                           as `text` or `json` (opt engine only)
+      --max-steps <N>      Stop after N instructions
+      --max-output <BYTES> Stop before writing more than BYTES bytes
+      --max-cells <N>      Stop once the cells used span more than N
+      --save-snapshot <FILE>
+                           If the run stops with an error or at a limit, save its state to FILE
+      --resume <FILE>      Carry on from a snapshot of a run of the same program. Its tape and
+                           EOF settings apply, with --max-* limits counting from the original start
+  -o, --output <FILE>      Where `compile` writes the bytecode, or `fmt` the source
+      --strip              Leave the source map out of the bytecode
+      --width <COLUMNS>    Line width for `fmt` [default: 80]
+      --indent <SPACES>    Indentation per loop level for `fmt` [default: 4]
+      --keep-comments      Keep comments in `fmt` output
+  -h, --help               Print this message
+
+Exit status:
+  0  program halted normally
+  1  bad arguments or unreadable files
+  2  program failed to parse or compile
+  3  runtime error
+  4  a --max-* limit was reached";
+
+const EXIT_USAGE: i32 = 1;
+const EXIT_PARSE: i32 = 2;
+const EXIT_RUNTIME: i32 = 3;
+const EXIT_LIMIT: i32 = 4;
+
+enum Source {
+    File(String),
+    Stdin,
+    Inline(String),
+}
+
+enum Engine {
+    Optimizing,
+    Token,
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    Jit,
+}
+
+enum Command {
+    Run,
+    Debug,
+    Compile,
+    Disasm,
+    Fmt,
+}
+
+enum Emit {
+    Brainfuck,
+    C,
+    Rust,
+    Wat,
+    Wasm,
+}
+
+enum Report {
+    Text,
+    Json,
+}
+
+// Settings given on the command line, which take precedence over those saved
+// in bytecode
+#[derive(Default)]
+struct Given {
+    tape_size: Option<usize>,
+    tape_policy: Option<bf::TapePolicy>,
+    eof_policy: Option<bf::EofPolicy>,
+    cell_width: Option<bf::CellWidth>,
+}
+
+struct Options {
+    command: Command,
+    source: Source,
+    input: Option<String>,
+    output: Option<String>,
+    source_map: bool,
+    layout: bf::Layout,
+    given: Given,
+    config: bf::Config,
+    cell_width: bf::CellWidth,
+    opt: bf::OptConfig,
+    engine: Engine,
+    emit: Option<Emit>,
+    profile: Option<Report>,
+    resume: Option<String>,
+    save_snapshot: Option<String>,
+}
+
+fn parse_args<I: Iterator<Item = String>>(args: I) -> Result<Options, String> {
+    let mut args = args.peekable();
+    let command = match args.peek().map(String::as_str) {
+        Some("run") => {
+            args.next();
+            Command::Run
+        }
+        Some("debug") => {
+            args.next();
+            Command::Debug
+        }
+        Some("compile") => {
+            args.next();
+            Command::Compile
+        }
+        Some("disasm") => {
+            args.next();
+            Command::Disasm
+        }
+        Some("fmt") => {
+            args.next();
+            Command::Fmt
+        }
+        _ => Command::Run,
+    };
+    let mut source = None;
+    let mut input = None;
+    let mut output = None;
+    let mut source_map = true;
+    let mut layout = bf::Layout::default();
+    let mut given = Given::default();
+    let mut config = bf::Config::new();
+    let mut opt = bf::OptConfig::default();
+    // Passes named on their own, applied over the level wherever it is given
+    let mut passes = Vec::new();
+    let mut engine = Engine::Optimizing;
+    let mut emit = None;
+    let mut profile = None;
+    let mut resume = None;
+    let mut save_snapshot = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| format!("missing value for `{}`", name))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            "-e" | "--eval" => source = Some(Source::Inline(value(&arg)?)),
+            "-i" | "--input" => input = Some(value(&arg)?),
+            "-o" | "--output" => output = Some(value(&arg)?),
+            "--strip" => source_map = false,
+            "--width" => layout.width = number(&arg, value(&arg)?)?,
+            "--indent" => layout.indent = number(&arg, value(&arg)?)?,
+            "--keep-comments" => layout.comments = true,
+            "-t" | "--tape-size" => {
+                let v = value(&arg)?;
+                given.tape_size = match v.parse() {
+                    Ok(n) if n > 0 => Some(n),
+                    _ => return Err(format!("invalid tape size `{}`", v)),
+                };
+            }
+            "-w" | "--cell-width" => given.cell_width = Some(value(&arg)?.parse()?),
+            "--tape" => given.tape_policy = Some(value(&arg)?.parse()?),
+            "--eof" => given.eof_policy = Some(value(&arg)?.parse()?),
+            "-O" => opt = level(&value(&arg)?)?,
+            a if a.starts_with("-O") => opt = level(&a[2..])?,
+            "--enable-pass" => passes.push((value(&arg)?.parse()?, true)),
+            "--disable-pass" => passes.push((value(&arg)?.parse()?, false)),
+            "--engine" => {
+                engine = match value(&arg)?.as_str() {
+                    "opt" => Engine::Optimizing,
+                    "old" => Engine::Token,
+                    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+                    "jit" => Engine::Jit,
+                    e => return Err(format!("unknown engine `{}`", e)),
+                };
+            }
+            "--emit" => {
+                emit = match value(&arg)?.as_str() {
+                    "bf" => Some(Emit::Brainfuck),
+                    "c" => Some(Emit::C),
+                    "rust" => Some(Emit::Rust),
+                    "wat" => Some(Emit::Wat),
+                    "wasm" => Some(Emit::Wasm),
+                    l => return Err(format!("unknown language `{}`", l)),
+                };
+            }
+            "--max-steps" => config = config.max_steps(number(&arg, value(&arg)?)?),
+            "--max-output" => config = config.max_output(number(&arg, value(&arg)?)?),
+            "--max-cells" => config = config.max_cells(number(&arg, value(&arg)?)?),
+            "--profile" => {
+                profile = match value(&arg)?.as_str() {
+                    "text" => Some(Report::Text),
+                    "json" => Some(Report::Json),
+                    f => return Err(format!("unknown profile format `{}`", f)),
+                };
+            }
+            "--resume" => resume = Some(value(&arg)?),
+            "--save-snapshot" => save_snapshot = Some(value(&arg)?),
+            "-" => source = Some(Source::Stdin),
+            a if a.starts_with('-') => return Err(format!("unknown option `{}`", a)),
+            _ => {
+                if source.is_some() {
+                    return Err(format!("unexpected argument `{}`", arg));
+                }
+                source = Some(Source::File(arg));
+            }
+        }
+    }
+
+    let source = source.ok_or_else(|| "no program given".to_string())?;
+    for (pass, enable) in passes {
+        opt = if enable { opt.enable(pass) } else { opt.disable(pass) };
+    }
+    if let (Command::Compile, None) = (&command, &output) {
+        output = match &source {
+            Source::File(path) => Some(Path::new(path).with_extension("bfc").to_string_lossy().into_owned()),
+            _ => return Err("`compile` needs `-o` when the program is not a file".to_string()),
+        };
+    }
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    if matches!(engine, Engine::Jit) && !config.limits.is_unlimited() {
+        return Err("the `jit` engine cannot enforce `--max-*` limits".to_string());
+    }
+    if emit.is_some() && !config.limits.is_unlimited() {
+        return Err("`--max-*` limits do not apply to `--emit`".to_string());
+    }
+    if matches!(command, Command::Compile) && !config.limits.is_unlimited() {
+        return Err("`--max-*` limits are not saved in bytecode".to_string());
+    }
+    if profile.is_some() && !matches!(engine, Engine::Optimizing) {
+        return Err("`--profile` needs the `opt` engine".to_string());
+    }
+    if (resume.is_some() || save_snapshot.is_some()) && !matches!(engine, Engine::Optimizing) {
+        return Err("snapshots need the `opt` engine".to_string());
+    }
+    let (config, cell_width) = given.apply(&config, bf::CellWidth::default());
+    Ok(Options {
+        command,
+        source,
+        input,
+        output,
+        source_map,
+        layout,
+        given,
+        config,
+        cell_width,
+        opt,
+        engine,
+        emit,
+        profile,
+        resume,
+        save_snapshot,
+    })
+}
+
+impl Given {
+    // `config` and `width` with the given settings in place of theirs
+    fn apply(&self, config: &bf::Config, width: bf::CellWidth) -> (bf::Config, bf::CellWidth) {
+        let mut config = config.clone();
+        if let Some(n) = self.tape_size {
+            config.tape_size = n;
+        }
+        if let Some(policy) = self.tape_policy {
+            config.tape_policy = policy;
+        }
+        if let Some(policy) = self.eof_policy {
+            config.eof_policy = policy;
+        }
+        (config, self.cell_width.unwrap_or(width))
+    }
+}
+
+fn level(v: &str) -> Result<bf::OptConfig, String> {
+    v.parse()
+        .ok()
+        .and_then(bf::OptConfig::level)
+        .ok_or_else(|| format!("invalid optimization level `{}`", v))
+}
+
+fn number<N: std::str::FromStr>(name: &str, v: String) -> Result<N, String> {
+    v.parse().map_err(|_| format!("invalid value `{}` for `{}`", v, name))
+}
+
+fn read_source(source: &Source) -> io::Result<Vec<u8>> {
+    match source {
+        Source::File(path) => {
+            let mut buf = Vec::new();
+            File::open(path)?.read_to_end(&mut buf)?;
+            Ok(buf)
+        }
+        Source::Stdin => {
+            let mut buf = Vec::new();
+            io::stdin().read_to_end(&mut buf)?;
+            Ok(buf)
+        }
+        Source::Inline(code) => Ok(code.clone().into_bytes()),
+    }
+}
+
+fn execute<C: bf::Cell, T: Read, U: Write>(
+    opts: &Options,
+    source: &[u8],
+    program: Option<&bf::Program>,
+    input: &mut T,
+    output: &mut U,
+) -> (bf::Result<()>, bf::Usage) {
+    match (&opts.engine, program) {
+        (Engine::Token, _) | (_, None) => {
+            let t = bf::Lexer::new(source);
+            let mut bf = bf::OldInterpreter::<C, _, _, _>::with_config(t, input, output, opts.config.clone());
+            (bf.run(), bf.usage())
+        }
+        #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+        (Engine::Jit, Some(c)) => {
+            let result = bf::Jit::<C>::compile(c, opts.config.clone()).and_then(|jit| jit.run(input, output));
+            (result, bf::Usage::default())
+        }
+        (Engine::Optimizing, Some(c)) => match &opts.profile {
+            None => {
+                let mut bf = bf::Interpreter::<C, _, _>::with_config(c, input, output, opts.config.clone());
+                (run_vm(opts, &mut bf), bf.usage())
+            }
+            Some(report) => {
+                let profile = bf::Profile::new(c);
+                let mut bf = bf::Interpreter::<C, _, _, _>::with_observer(c, input, output, opts.config.clone(), profile);
+                let start = Instant::now();
+                let result = run_vm(opts, &mut bf);
+                let elapsed = start.elapsed();
+                let usage = bf.usage();
+                let profile = bf.into_observer();
+                let _ = output.flush();
+                let se_r = io::stderr();
+                let mut se = se_r.lock();
+                let written = match report {
+                    Report::Text => profile.write_text(c, elapsed, &mut se),
+                    Report::Json => profile.write_json(c, elapsed, &mut se),
+                };
+                if let Err(e) = written {
+                    fail(EXIT_USAGE, &format!("cannot write profile: {}", e));
+                }
+                (result, usage)
+            }
+        },
+    }
+}
+
+// Runs `vm`, first restoring and afterwards saving a snapshot if asked to
+fn run_vm<C: bf::Cell, T: Read, U: Write, O: bf::Observer>(
+    opts: &Options,
+    vm: &mut bf::Interpreter<C, T, U, O>,
+) -> bf::Result<()> {
+    if let Some(path) = &opts.resume {
+        let snapshot = File::open(path)
+            .map_err(|e| bf::Error::Io(e, None))
+            .and_then(|mut f| bf::Snapshot::read_from(&mut f))
+            .and_then(|mut snapshot| {
+                snapshot.set_limits(opts.config.limits);
+                vm.restore(snapshot)
+            });
+        if let Err(e) = snapshot {
+            let code = match e {
+                bf::Error::LimitExceeded(..) => EXIT_LIMIT,
+                _ => EXIT_USAGE,
+            };
+            fail(code, &format!("cannot resume from `{}`: {}", path, e));
+        }
+    }
+    let result = vm.run();
+    if let (Err(_), Some(path)) = (&result, &opts.save_snapshot) {
+        if let Err(e) = File::create(path).and_then(|mut f| vm.snapshot().write_to(&mut f)) {
+            eprintln!("bfinterp: cannot save snapshot to `{}`: {}", path, e);
+        }
+    }
+    result
+}
+
+fn debug<C: bf::Cell, T: Read>(
+    opts: &Options,
+    source: &[u8],
+    program: &bf::Program,
+    input: &mut T,
+) -> io::Result<()> {
+    let mut output = io::stdout();
+    let vm = bf::Interpreter::<C, _, _>::with_config(program, input, &mut output, opts.config.clone());
+    debug::run(program, source, vm)
+}
+
+fn compile(nodes: Vec<bf::Node>, opt: &bf::OptConfig) -> bf::Program {
+    bf::generate_code_with(nodes, opt).unwrap_or_else(|e| fail(EXIT_PARSE, &e.to_string()))
+}
+
+fn fail(code: i32, msg: &str) -> ! {
+    eprintln!("bfinterp: {}", msg);
+    process::exit(code)
+}
 
 fn main() {
-    let r = BufReader::new(File::open("bf/mandelbrot.bf").unwrap());
+    let mut opts = parse_args(env::args().skip(1))
+        .unwrap_or_else(|e| fail(EXIT_USAGE, &format!("{}\n\n{}", e, USAGE)));
 
-    let t = bf::Lexer::new(r);
-    let p = bf::Parser::new(t);
-    let mut c = bf::generate_code(p.collect());
-    c.reverse();
+    let source = read_source(&opts.source)
+        .unwrap_or_else(|e| fail(EXIT_USAGE, &format!("cannot read program: {}", e)));
+    if let Command::Fmt = opts.command {
+        if bf::Bytecode::is_bytecode(&source) {
+            fail(EXIT_USAGE, "`fmt` formats source, not bytecode");
+        }
+        let text = bf::format(&source, &opts.layout).unwrap_or_else(|e| fail(EXIT_PARSE, &e.to_string()));
+        let written = match &opts.output {
+            Some(path) => File::create(path).and_then(|mut f| f.write_all(text.as_bytes())),
+            None => io::stdout().write_all(text.as_bytes()),
+        };
+        if let Err(e) = written {
+            fail(EXIT_USAGE, &format!("cannot write output: {}", e));
+        }
+        return;
+    }
 
-    // println!("{:?}", c);
-    // println!("total instructions: {:?}", c.len());
+    let (source, program) = if bf::Bytecode::is_bytecode(&source) {
+        let bytecode = bf::Bytecode::read_from(&mut &source[..]).unwrap_or_else(|e| fail(EXIT_PARSE, &e.to_string()));
+        let config = bytecode.config.limits(opts.config.limits);
+        let (config, cell_width) = opts.given.apply(&config, bytecode.cell_width);
+        opts.config = config;
+        opts.cell_width = cell_width;
+        if let (Engine::Token, Command::Run, None) = (&opts.engine, &opts.command, &opts.emit) {
+            fail(EXIT_USAGE, "the `old` engine runs source, not bytecode");
+        }
+        (Vec::new(), Some(bytecode.program))
+    } else {
+        // Parse up front even for the token-level engine, so syntax errors never start a run
+        let nodes = bf::Parser::new(bf::Lexer::new(&source[..]))
+            .collect::<bf::Result<Vec<_>>>()
+            .unwrap_or_else(|e| fail(EXIT_PARSE, &e.to_string()));
+        let program = match (&opts.engine, &opts.command, &opts.emit) {
+            (Engine::Token, Command::Run, None) => None,
+            _ => Some(compile(nodes, &opts.opt)),
+        };
+        (source, program)
+    };
 
-    let si_r = stdin();
-    let so_r = stdout();
-    let mut si = si_r.lock();
+    if let Command::Compile = opts.command {
+        let bytecode = bf::Bytecode {
+            program: program.unwrap_or_default(),
+            cell_width: opts.cell_width,
+            config: opts.config.clone(),
+        };
+        let path = opts.output.as_deref().unwrap_or_default();
+        if let Err(e) = File::create(path).and_then(|mut f| bytecode.write_to(&mut f, opts.source_map)) {
+            fail(EXIT_USAGE, &format!("cannot write `{}`: {}", path, e));
+        }
+        return;
+    }
+
+    if let (Command::Disasm, Some(program)) = (&opts.command, &program) {
+        let so_r = io::stdout();
+        let mut so = so_r.lock();
+        let source = if source.is_empty() { None } else { Some(&source[..]) };
+        if let Err(e) = bf::disassemble(program, source, &mut so).and_then(|_| so.flush()) {
+            fail(EXIT_USAGE, &format!("cannot write output: {}", e));
+        }
+        return;
+    }
+
+    if let (Some(lang), Some(program)) = (&opts.emit, &program) {
+        let so_r = io::stdout();
+        let mut so = so_r.lock();
+        let result = match lang {
+            Emit::Brainfuck => bf::backend::brainfuck::emit(program, opts.cell_width, &mut so),
+            Emit::C => bf::backend::c::emit(program, opts.cell_width, &opts.config, &mut so),
+            Emit::Rust => bf::backend::rust::emit(program, opts.cell_width, &opts.config, &mut so),
+            Emit::Wat => bf::backend::wasm::emit_wat(program, opts.cell_width, &opts.config, &mut so),
+            Emit::Wasm => bf::backend::wasm::emit_wasm(program, opts.cell_width, &opts.config, &mut so),
+        };
+        if let Err(e) = result.and_then(|_| so.flush()) {
+            fail(EXIT_USAGE, &format!("cannot write output: {}", e));
+        }
+        return;
+    }
+
+    let mut input: Box<dyn Read> = match &opts.input {
+        Some(path) => match File::open(path) {
+            Ok(f) => Box::new(BufReader::new(f)),
+            Err(e) => fail(EXIT_USAGE, &format!("cannot open input `{}`: {}", path, e)),
+        },
+        None => Box::new(io::stdin()),
+    };
+
+    if let (Command::Debug, Some(program)) = (&opts.command, &program) {
+        // The debugger's commands come from stdin, so the program's input can't
+        if opts.input.is_none() && program.instructions.contains(&bf::Instruction::Read) {
+            fail(EXIT_USAGE, "`debug` reads commands from stdin; give the program's input with -i");
+        }
+        let result = match opts.cell_width {
+            bf::CellWidth::U8 => debug::<u8, _>(&opts, &source, program, &mut input),
+            bf::CellWidth::U16 => debug::<u16, _>(&opts, &source, program, &mut input),
+            bf::CellWidth::U32 => debug::<u32, _>(&opts, &source, program, &mut input),
+            bf::CellWidth::U64 => debug::<u64, _>(&opts, &source, program, &mut input),
+        };
+        if let Err(e) = result {
+            fail(EXIT_USAGE, &format!("i/o error: {}", e));
+        }
+        return;
+    }
+
+    let so_r = io::stdout();
     let mut so = so_r.lock();
+    let program = program.as_ref();
+
+    let (result, usage) = match opts.cell_width {
+        bf::CellWidth::U8 => execute::<u8, _, _>(&opts, &source, program, &mut input, &mut so),
+        bf::CellWidth::U16 => execute::<u16, _, _>(&opts, &source, program, &mut input, &mut so),
+        bf::CellWidth::U32 => execute::<u32, _, _>(&opts, &source, program, &mut input, &mut so),
+        bf::CellWidth::U64 => execute::<u64, _, _>(&opts, &source, program, &mut input, &mut so),
+    };
+    let _ = so.flush();
 
-    let mut bf = bf::Interpreter::new(&c, &mut si, &mut so);
-    bf.run().unwrap();
+    match result {
+        Ok(()) => {}
+        Err(e @ bf::Error::LimitExceeded(..)) => fail(
+            EXIT_LIMIT,
+            &format!(
+                "{} after {} steps, {} bytes of output and {} tape cells",
+                e, usage.steps, usage.output, usage.cells
+            ),
+        ),
+        Err(e @ bf::Error::Unsupported(_)) => fail(EXIT_USAGE, &e.to_string()),
+        Err(e) => fail(EXIT_RUNTIME, &e.to_string()),
+    }
 }
@@ -0,0 +1,74 @@
+// Round trips through bf::backend::brainfuck
+
+mod common;
+
+use common::{compile, parse, EXCURSIONS, PROGRAMS};
+
+use bfinterp::bf;
+use bfinterp::bf::{CellWidth, Instruction};
+use bfinterp::bf::Instruction::*;
+
+use std::io;
+
+#[test]
+fn instructions_compile_back_to_themselves() {
+    let excursions = EXCURSIONS.iter().map(|&(source, _)| (source, source));
+    for (name, source) in PROGRAMS.iter().copied().chain(excursions) {
+        let program = compile(source.as_bytes());
+        let mut text = Vec::new();
+        bf::backend::brainfuck::emit(&program, CellWidth::U8, &mut text).unwrap();
+        assert_eq!(compile(&text).instructions, program.instructions, "{}", name);
+        assert!(text.len() <= source.len() + 1, "{}", name);
+    }
+}
+
+#[test]
+fn nodes_emit_only_net_runs() {
+    let mut text = Vec::new();
+    bf::backend::brainfuck::emit_nodes(&parse(b"a+-+ +>><<<. [->+<] comment ,"), &mut text).unwrap();
+    assert_eq!(text, b"++>><<<.[->+<],\n");
+
+    let excursions = EXCURSIONS.iter().map(|&(source, _)| (source, source));
+    for (name, source) in PROGRAMS.iter().copied().chain(excursions) {
+        let mut text = Vec::new();
+        bf::backend::brainfuck::emit_nodes(&parse(source.as_bytes()), &mut text).unwrap();
+        let mut again = Vec::new();
+        bf::backend::brainfuck::emit_nodes(&parse(&text), &mut again).unwrap();
+        assert_eq!(again, text, "{}", name);
+        assert_eq!(compile(&text).instructions, compile(source.as_bytes()).instructions, "{}", name);
+    }
+}
+
+#[test]
+fn adds_take_the_short_way_round() {
+    let program = compile("+".repeat(250).as_bytes());
+    let mut text = Vec::new();
+    bf::backend::brainfuck::emit(&program, CellWidth::U8, &mut text).unwrap();
+    assert_eq!(text, b"------\n");
+    text.clear();
+    bf::backend::brainfuck::emit(&program, CellWidth::U16, &mut text).unwrap();
+    assert_eq!(text.len(), 251);
+}
+
+#[test]
+fn operands_too_large_to_write_are_refused() {
+    let emit = |instructions: &[Instruction], width| {
+        let program = bf::Program {
+            instructions: instructions.to_vec(),
+            spans: Vec::new(),
+        };
+        let mut text = Vec::new();
+        bf::backend::brainfuck::emit(&program, width, &mut text).map(|()| text)
+    };
+    assert_eq!(emit(&[Add(0, i64::MAX), Halt], CellWidth::U8).unwrap(), b"-\n");
+    assert_eq!(emit(&[MulAdd(1, i64::MIN), Zero, Halt], CellWidth::U16).unwrap(), b"[-><]\n");
+    for instructions in [
+        &[Add(0, i64::MAX), Halt][..],
+        &[Set(0, 1 << 40), Halt],
+        &[MulAdd(1, -(1 << 40)), Zero, Halt],
+        &[Write(i32::MIN), Halt],
+    ] {
+        let e = emit(instructions, CellWidth::U64).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{:?}", instructions);
+    }
+}
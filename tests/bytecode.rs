@@ -0,0 +1,145 @@
+// Saving compiled programs with bf::Bytecode and running them from the
+// command line
+
+mod common;
+
+use common::{compile, FACTOR};
+
+use bfinterp::bf::{self, Bytecode, CellWidth, Config, EofPolicy, Error, Instruction, TapePolicy};
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+fn bytecode(program: bf::Program) -> Bytecode {
+    Bytecode {
+        program,
+        cell_width: CellWidth::U16,
+        config: Config::new()
+            .tape_size(1000)
+            .tape_policy(TapePolicy::Error)
+            .eof_policy(EofPolicy::Zero),
+    }
+}
+
+fn saved(bytecode: &Bytecode, source_map: bool) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytecode.write_to(&mut bytes, source_map).unwrap();
+    bytes
+}
+
+fn read(bytes: &[u8]) -> bf::Result<Bytecode> {
+    Bytecode::read_from(&mut &bytes[..])
+}
+
+#[test]
+fn round_trip() {
+    let original = bytecode(compile(FACTOR.as_bytes()));
+    let bytes = saved(&original, true);
+    assert!(Bytecode::is_bytecode(&bytes));
+    assert_eq!(read(&bytes).unwrap(), original);
+
+    let stripped = read(&saved(&original, false)).unwrap();
+    assert!(stripped.program.spans.is_empty());
+    assert_eq!(stripped.program.instructions, original.program.instructions);
+    assert_eq!((stripped.cell_width, &stripped.config), (original.cell_width, &original.config));
+}
+
+#[test]
+fn limits_are_not_saved() {
+    let mut original = bytecode(compile(b"+."));
+    original.config = original.config.max_steps(10);
+    assert!(read(&saved(&original, true)).unwrap().config.limits.is_unlimited());
+}
+
+#[test]
+fn truncated_and_corrupt_files_are_rejected() {
+    let bytes = saved(&bytecode(compile(b"+[>+<-]>.")), true);
+    for len in 0..bytes.len() {
+        assert!(matches!(read(&bytes[..len]), Err(Error::Format(_))), "{} bytes", len);
+    }
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert!(matches!(read(&trailing), Err(Error::Format(_))));
+
+    let mut version = bytes.clone();
+    version[6] = 99;
+    assert!(matches!(read(&version), Err(Error::Format(msg)) if msg.contains("version")));
+
+    // The first opcode follows the header and the instruction count, which is
+    // where the map flag is in a file without instructions
+    let empty = saved(&bytecode(bf::Program::default()), false);
+    let mut opcode = bytes;
+    opcode[empty.len() - 1] = 0xff;
+    assert!(matches!(read(&opcode), Err(Error::Format(msg)) if msg.contains("unknown opcode")));
+}
+
+#[test]
+fn unsafe_programs_are_rejected() {
+    let programs: &[&[Instruction]] = &[
+        &[],
+        &[Instruction::Add(0, 1)],
+        &[Instruction::Jez(2), Instruction::Halt],
+        &[Instruction::Jez(1), Instruction::Jnz(2), Instruction::Halt],
+        &[Instruction::Jnz(1), Instruction::Halt],
+    ];
+    for &instructions in programs {
+        let program = bf::Program {
+            instructions: instructions.to_vec(),
+            spans: Vec::new(),
+        };
+        let bytes = saved(&bytecode(program), false);
+        assert!(matches!(read(&bytes), Err(Error::Format(_))), "{:?}", instructions);
+    }
+
+    let program = bf::Program {
+        instructions: vec![Instruction::Jez(1), Instruction::Jnz(1), Instruction::Halt],
+        spans: Vec::new(),
+    };
+    assert!(read(&saved(&bytecode(program), false)).is_ok());
+}
+
+// A file in the temporary directory, unique to this test run
+fn temp(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("bfinterp-{}-{}", std::process::id(), name))
+}
+
+fn bfinterp(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_bfinterp"))
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn compiled_files_run_with_their_settings() {
+    let (source, compiled) = (temp("eof.bf"), temp("eof.bfc"));
+    fs::write(&source, ",.<").unwrap();
+    let compile = bfinterp(&[
+        "compile",
+        "--eof",
+        "max",
+        "--tape",
+        "error",
+        source.to_str().unwrap(),
+        "-o",
+        compiled.to_str().unwrap(),
+    ]);
+    assert!(compile.status.success(), "{}", String::from_utf8_lossy(&compile.stderr));
+    let compiled = compiled.to_str().unwrap();
+
+    // Saved settings: -1 at the end of input, then off the left end
+    let run = bfinterp(&[compiled]);
+    assert_eq!(run.stdout, [0xff]);
+    assert_eq!(run.status.code(), Some(3));
+
+    // Overridden on the command line
+    let run = bfinterp(&["--eof", "zero", "--tape", "wrap", compiled]);
+    assert_eq!(run.stdout, [0]);
+    assert_eq!(run.status.code(), Some(0));
+
+    let _ = fs::remove_file(source);
+    let _ = fs::remove_file(compiled);
+}
@@ -0,0 +1,45 @@
+// Sample programs and helpers shared by the integration tests. Each test
+// crate uses only some of them.
+#![allow(dead_code)]
+
+use bfinterp::bf;
+
+pub const HELLO: &str = include_str!("../../bf/hello.bf");
+pub const FACTOR: &str = include_str!("../../bf/factor.bf");
+pub const MANDELBROT: &str = include_str!("../../bf/mandelbrot.bf");
+
+/// Every program in bf/, by name
+pub const PROGRAMS: &[(&str, &str)] = &[
+    ("hello", HELLO),
+    ("factor", FACTOR),
+    ("mandelbrot", MANDELBROT),
+    ("move", include_str!("../../bf/move.bf")),
+    ("zero", include_str!("../../bf/zero.bf")),
+    ("zeromore", include_str!("../../bf/zeromore.bf")),
+];
+
+/// Runs that go off a tape of 5 cells and come back, and where each first
+/// leaves it
+pub const EXCURSIONS: &[(&str, &str)] = &[
+    ("<>.", "1:1"),
+    ("<>+.", "1:1"),
+    (">>>><<<<<+>>.", "1:9"),
+    ("+>+<<>>.", "1:5"),
+    ("+[<>-]", "1:3"),
+    ("[>]<>-[>>+<-<]", "1:4"),
+    ("+>>+<<[-<<>>]", "1:9"),
+];
+
+pub fn parse(source: &[u8]) -> Vec<bf::Node> {
+    bf::Parser::new(bf::Lexer::new(source))
+        .collect::<bf::Result<Vec<_>>>()
+        .unwrap()
+}
+
+pub fn compile(source: &[u8]) -> bf::Program {
+    bf::generate_code(parse(source)).unwrap()
+}
+
+pub fn compile_with(source: &[u8], opt: &bf::OptConfig) -> bf::Program {
+    bf::generate_code_with(parse(source), opt).unwrap()
+}
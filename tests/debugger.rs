@@ -0,0 +1,62 @@
+// Breakpoints in bf::Debugger
+
+mod common;
+
+use common::compile_with;
+
+use bfinterp::bf::{self, Debugger, OptConfig, Stop};
+
+// Sets breakpoints from the `#`s in `source`, then resumes until the program
+// halts, returning where each stop was and the output
+fn breaks(source: &str) -> (Vec<usize>, Vec<u8>) {
+    let program = compile_with(source.as_bytes(), &OptConfig::none());
+    let mut input: &[u8] = b"";
+    let mut output = Vec::new();
+    let vm = bf::Interpreter::<u8, _, _>::new(&program, &mut input, &mut output);
+    let mut dbg = Debugger::new(vm, &program);
+    dbg.break_on_hashes(source.as_bytes());
+    let mut stops = Vec::new();
+    loop {
+        match dbg.resume() {
+            Stop::Breakpoint(pc) => stops.push(pc),
+            Stop::Halted => break,
+            stop => panic!("{}: {:?}", source, stop),
+        }
+    }
+    drop(dbg);
+    (stops, output)
+}
+
+#[test]
+fn breakpoint_on_the_first_instruction() {
+    assert_eq!(breaks("#+++."), (vec![0], vec![3]));
+}
+
+#[test]
+fn breakpoint_in_a_loop_stops_each_time_round() {
+    // add 0 3, jez, add 0 -1, write 0, jnz
+    assert_eq!(breaks("+++[-#.]"), (vec![3, 3, 3], vec![2, 1, 0]));
+}
+
+#[test]
+fn running_on_passes_the_breakpoint_stopped_at() {
+    // add 0 1, right 1, write 0
+    let program = compile_with(b"+#>.", &OptConfig::none());
+    let mut input: &[u8] = b"";
+    let mut output = Vec::new();
+    let vm = bf::Interpreter::<u8, _, _>::new(&program, &mut input, &mut output);
+    let mut dbg = Debugger::new(vm, &program);
+    assert_eq!(dbg.break_on_hashes(b"+#>."), [1]);
+    assert!(matches!(dbg.step(), Stop::Step));
+    assert_eq!(dbg.interpreter().pc(), 1);
+    assert!(matches!(dbg.resume(), Stop::Halted));
+
+    let mut input: &[u8] = b"";
+    let mut output = Vec::new();
+    let vm = bf::Interpreter::<u8, _, _>::new(&program, &mut input, &mut output);
+    let mut dbg = Debugger::new(vm, &program);
+    dbg.break_on_hashes(b"+#>.");
+    assert!(matches!(dbg.resume(), Stop::Breakpoint(1)));
+    assert!(matches!(dbg.step_over(), Stop::Step));
+    assert!(matches!(dbg.resume(), Stop::Halted));
+}
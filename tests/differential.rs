@@ -0,0 +1,307 @@
+// Runs programs through the token-level interpreter and through the VM on raw
+// code and at each optimization level, and through the JIT when it is built,
+// which must agree on the output, how the run ended, the final tape and where
+// the pointer ends up
+
+mod common;
+
+use common::{compile_with, FACTOR, HELLO, MANDELBROT, PROGRAMS};
+
+use bfinterp::bf;
+use bfinterp::bf::{Cell, Config, Error, Limit, OptConfig, TapePolicy};
+
+use std::fmt::Debug;
+
+// Small programs aimed at each pass and the seams between them
+const CORPUS: &[&str] = &[
+    "",
+    "+-+-",
+    "+++.>++.<<-.",
+    "+++++[-]+.",
+    "++++[+]-.",
+    "+++[[-]]>+",
+    "++>+++>++++<<[-]>[-]>[-]",
+    ">>>+++<[-]<[-]<[-]>>.",
+    "+>+>+>+<<<[>]<.",
+    ">>>+>+>+[<]>.",
+    "+++[>+<-]>.",
+    "+++[->+<]>.",
+    "+++[-<+>]<.",
+    "+++[->>+++<<]>>.",
+    "++++[->+>--<<]>.>.",
+    "+++[->++>+++<<<+>]",
+    "--[+>+<]>.",
+    "++[->+<<+>]",
+    "+++++[->+<>+<]>.",
+    "+++[->+>[-]<<]",
+    ">+++++[>+[->+<]<<]",
+    ">+>+++[>[->+<]<<]>>>.",
+    "+[>>+<[->+<]<-]",
+    ">+>+<<[>[->+<]<]",
+    "+>>>+<.<<.>>>>.",
+    "++>+++<[->>>+<<<]>[-<+>]<.",
+    ",.,.,.,[-].",
+    ",[.[-],]",
+    ",>,<[->+<]>.",
+    "+[-->-[>>+>-----<<]<--<---]>-.>>>+.>>..+++[.>]<<<<.+++.------.<<-.>>>>+.",
+    "<<<+>>>[-]<<<.",
+    "+[<+>>+<-]<.>>.",
+    "++++++++[>++++++++<-]>[<++++>-]+<[>-<[>++++<-]>[<++++++++>-]<[>++++++++<-]+>[>++++++++++[>+++++<-]>+.-.[-]<<[-]<->] <[>>+++++++[>+++++++<-]>.+++++.[-]<<<-]] >[>++++++++[>+++++++<-]>.[-]<<-]<+++++++++++[>+++>+++++++++>+++++++++>+<<<<-]>-.>-.+++++++.+++++++++++.<.>>.++.+++++++..<-.>>-[[-]<]",
+];
+
+// What a run left behind. The tape and pointer are only compared after it
+// halts, as fused instructions fail before doing any of their work.
+#[derive(Debug)]
+struct Run<C> {
+    output: Vec<u8>,
+    error: Option<Failure>,
+    tape: Vec<C>,
+    ptr: usize,
+}
+
+// An error's message, without where it happened, and where
+#[derive(Debug)]
+struct Failure {
+    message: String,
+    offset: Option<usize>,
+}
+
+impl Failure {
+    fn new(e: Error) -> Self {
+        let offset = e.position().map(|pos| pos.offset);
+        let message = match e {
+            Error::TapeOutOfBounds(_) => Error::TapeOutOfBounds(None),
+            Error::LimitExceeded(limit, _) => Error::LimitExceeded(limit, None),
+            Error::Io(e, _) => Error::Io(e, None),
+            e => e,
+        };
+        Failure {
+            message: message.to_string(),
+            offset,
+        }
+    }
+
+    // Fused instructions report the start of their source, so only code
+    // that follows the commands fails at exactly the same one
+    fn agrees(this: &Option<Failure>, expected: &Option<Failure>, exact: bool) -> bool {
+        match (this, expected) {
+            (None, None) => true,
+            (Some(e), Some(expected)) => {
+                e.message == expected.message
+                    && match (e.offset, expected.offset) {
+                        (Some(at), Some(expected)) => at == expected || (!exact && at < expected),
+                        (at, expected) => at == expected,
+                    }
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<C: Cell> Run<C> {
+    fn new(output: Vec<u8>, result: bf::Result<()>, tape: &[C], ptr: usize, policy: TapePolicy) -> Self {
+        let (tape, ptr) = match policy {
+            TapePolicy::Wrap | TapePolicy::Error => (tape.to_vec(), ptr),
+            TapePolicy::GrowRight | TapePolicy::Grow => used(tape, ptr),
+        };
+        Run {
+            output,
+            error: result.err().map(Failure::new),
+            tape,
+            ptr,
+        }
+    }
+
+    fn agrees(&self, expected: &Run<C>, exact: bool) -> bool {
+        self.output == expected.output
+            && Failure::agrees(&self.error, &expected.error, exact)
+            && (self.error.is_some() || (self.tape == expected.tape && self.ptr == expected.ptr))
+    }
+}
+
+// The cells from the first to the last that is nonzero or under the pointer,
+// and the pointer among them. Engines grow the tape by different amounts, so
+// only this part of a grown tape is compared.
+fn used<C: Cell>(tape: &[C], ptr: usize) -> (Vec<C>, usize) {
+    let first = tape.iter().position(|c| !c.is_zero()).map_or(ptr, |i| i.min(ptr));
+    let last = tape.iter().rposition(|c| !c.is_zero()).map_or(ptr, |i| i.max(ptr));
+    (tape[first..=last].to_vec(), ptr - first)
+}
+
+// None if the run takes more than `config`'s step limit
+fn token_level<C: Cell>(source: &str, input: &[u8], config: &Config) -> Option<(Run<C>, bf::Usage)> {
+    let mut input = input;
+    let mut output = Vec::new();
+    let tokens = bf::Lexer::new(source.as_bytes());
+    let mut bf = bf::OldInterpreter::<C, _, _, _>::with_config(tokens, &mut input, &mut output, config.clone());
+    let result = bf.run();
+    if let Err(Error::LimitExceeded(Limit::Steps, _)) = result {
+        return None;
+    }
+    let (tape, ptr, usage) = (bf.tape().to_vec(), bf.ptr(), bf.usage());
+    Some((Run::new(output, result, &tape, ptr, config.tape_policy), usage))
+}
+
+fn vm<C: Cell>(program: &bf::Program, input: &[u8], config: &Config) -> Run<C> {
+    let mut input = input;
+    let mut output = Vec::new();
+    let mut bf = bf::Interpreter::<C, _, _>::with_config(program, &mut input, &mut output, config.clone());
+    let result = bf.run();
+    let (tape, ptr) = (bf.tape().to_vec(), bf.ptr());
+    Run::new(output, result, &tape, ptr, config.tape_policy)
+}
+
+// Output of the JIT and how its run ended; it keeps no tape to look at
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+fn jit<C: Cell>(program: &bf::Program, input: &[u8], config: &Config) -> (Vec<u8>, Option<Failure>) {
+    let mut input = input;
+    let mut output = Vec::new();
+    let config = config.clone().limits(bf::Limits::default());
+    let result = bf::Jit::<C>::compile(program, config).and_then(|jit| jit.run(&mut input, &mut output));
+    (output, result.err().map(Failure::new))
+}
+
+// Output of `source` with `input`, after checking every way of running it
+// gives the same result. None if the token-level interpreter takes more than
+// `config`'s step limit. The VM gets ample room over its count, only so that a
+// bug that keeps it looping fails the test instead of hanging it.
+fn check<C: Cell + Debug>(name: &str, source: &str, input: &[u8], config: &Config) -> Option<Vec<u8>> {
+    let (expected, usage) = token_level::<C>(source, input, config)?;
+    let bounded = config.clone().max_steps(usage.steps * 4 + 1000);
+    let levels = [
+        ("raw", OptConfig::none()),
+        ("level 1", OptConfig::level(1).unwrap()),
+        ("optimized", OptConfig::all()),
+    ];
+    for (level, opt) in levels {
+        let program = compile_with(source.as_bytes(), &opt);
+        let exact = opt == OptConfig::none();
+        let run = vm::<C>(&program, input, &bounded);
+        let what = format!("{} ({} code, {}-bit cells, {:?})", name, level, C::WIDTH.bits(), config.tape_policy);
+        assert!(run.agrees(&expected, exact), "{}: {:?}, expected {:?}", what, run.error, expected.error);
+        #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+        {
+            let (output, error) = jit::<C>(&program, input, config);
+            let agrees = output == expected.output && Failure::agrees(&error, &expected.error, exact);
+            assert!(agrees, "{} on the JIT: {:?}, expected {:?}", what, error, expected.error);
+        }
+    }
+    Some(expected.output)
+}
+
+fn check_widths(name: &str, source: &str, input: &[u8], config: &Config) -> Option<Vec<u8>> {
+    let output = check::<u8>(name, source, input, config);
+    check::<u16>(name, source, input, config);
+    check::<u32>(name, source, input, config);
+    check::<u64>(name, source, input, config);
+    output
+}
+
+#[test]
+fn hello() {
+    let output = check_widths("hello", HELLO, b"", &Config::new());
+    assert_eq!(output.unwrap(), b"Hello World!\n");
+}
+
+#[test]
+fn factor() {
+    let cases: &[(&[u8], &[u8])] = &[
+        (b"1\n", b"1: 1\n"),
+        (b"97\n", b"97: 97\n"),
+        (b"123456\n", b"123456: 2 2 2 2 2 2 3 643\n"),
+        (b"999999\n", b"999999: 3 3 3 7 11 13 37\n"),
+    ];
+    for &(input, expected) in cases {
+        let output = check::<u8>("factor", FACTOR, input, &Config::new());
+        assert_eq!(output.unwrap(), expected);
+    }
+}
+
+// The rest of bf/, which reads no input and runs quickly
+#[test]
+fn samples() {
+    for &(name, source) in PROGRAMS.iter().filter(|(name, _)| !["factor", "mandelbrot"].contains(name)) {
+        assert!(check_widths(name, source, b"", &Config::new()).is_some(), "{}", name);
+    }
+}
+
+// Leaving the tape is an error, or grows it, as well as wrapping around
+const POLICIES: [TapePolicy; 3] = [TapePolicy::Wrap, TapePolicy::Error, TapePolicy::Grow];
+
+#[test]
+fn corpus() {
+    for policy in POLICIES {
+        let config = Config::new().tape_size(64).tape_policy(policy).max_steps(1_000_000);
+        for source in CORPUS {
+            assert!(check_widths(source, source, b"in\n", &config).is_some(), "{}", source);
+        }
+    }
+}
+
+// The token-level interpreter needs minutes for this even in a release build:
+// cargo test --release -- --ignored
+#[test]
+#[ignore]
+fn mandelbrot() {
+    let output = check::<u8>("mandelbrot", MANDELBROT, b"", &Config::new());
+    assert!(output.unwrap() == include_bytes!("expected/mandelbrot.txt"));
+}
+
+// Without the token-level interpreter, the optimized code alone is quick
+// enough to check on every run
+#[test]
+fn mandelbrot_optimized() {
+    let program = compile_with(MANDELBROT.as_bytes(), &OptConfig::all());
+    let run = vm::<u8>(&program, b"", &Config::new());
+    assert!(run.error.is_none(), "{:?}", run.error);
+    assert!(run.output == include_bytes!("expected/mandelbrot.txt"));
+}
+
+// Minimal-standard LCG, so every run checks the same programs
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 = self.0 * 48271 % 0x7fff_ffff;
+        self.0 as usize % n
+    }
+}
+
+// Pieces the passes look for, and single commands to upset them
+const PIECES: &[&str] = &[
+    "[-]", "[+]", "[->+<]", "[+>-<]", "[-<<+>>]", "[->>+++<<]", "[-<+>>-<]", "[>]", "[<<]", "[>[->+<]<<]", "+", "-",
+    "+++", "---", ">", "<", ">>>", "<<", ".", ",",
+];
+
+fn program(rng: &mut Rng, depth: usize) -> String {
+    let mut text = String::new();
+    for _ in 0..1 + rng.below(8) {
+        match rng.below(6) {
+            0 if depth > 0 => {
+                text.push('[');
+                text.push_str(&program(rng, depth - 1));
+                text.push(']');
+            }
+            _ => text.push_str(PIECES[rng.below(PIECES.len())]),
+        }
+    }
+    text
+}
+
+#[test]
+fn generated() {
+    for policy in POLICIES {
+        let mut rng = Rng(1);
+        let config = Config::new().tape_size(16).tape_policy(policy).max_steps(100_000);
+        let mut finished = 0;
+        for _ in 0..2000 {
+            let source = program(&mut rng, 3);
+            if check::<u8>(&source, &source, b"\x01\x02\x03", &config).is_some() {
+                check::<u32>(&source, &source, b"\x01\x02\x03", &config);
+                finished += 1;
+            }
+        }
+        // Most programs should finish, or this checks little
+        assert!(finished > 1000, "only {} programs finished ({:?})", finished, policy);
+    }
+}
@@ -0,0 +1,50 @@
+// bf::format keeps commands and is idempotent
+
+mod common;
+
+use common::PROGRAMS;
+
+use bfinterp::bf;
+
+fn commands(text: &[u8]) -> Vec<u8> {
+    text.iter().copied().filter(|b| b"+-<>[].,".contains(b)).collect()
+}
+
+fn layouts() -> Vec<bf::Layout> {
+    let mut layouts = Vec::new();
+    for &comments in &[false, true] {
+        for &(indent, width) in &[(4, 80), (2, 40), (0, 1)] {
+            layouts.push(bf::Layout { indent, width, comments });
+        }
+    }
+    layouts
+}
+
+#[test]
+fn formatting_keeps_commands_and_is_idempotent() {
+    for &(name, source) in PROGRAMS {
+        for layout in layouts() {
+            let text = bf::format(source.as_bytes(), &layout).unwrap();
+            assert_eq!(commands(text.as_bytes()), commands(source.as_bytes()), "{} {:?}", name, layout);
+            let again = bf::format(text.as_bytes(), &layout).unwrap();
+            assert_eq!(again, text, "{} {:?}", name, layout);
+        }
+    }
+}
+
+#[test]
+fn loops_and_comments_are_laid_out() {
+    let source = b"set up ++++[ loop >++  >+++ + <<- ] done\n\n\nprint >.  end";
+    let layout = bf::Layout::default();
+    assert_eq!(bf::format(source, &layout).unwrap(), "++++ [>++>++++<<-] > .\n");
+    let layout = bf::Layout { comments: true, ..layout };
+    assert_eq!(
+        bf::format(source, &layout).unwrap(),
+        "set up\n++++\n[ loop\n    > ++ > ++++ << -\n] done\n\nprint\n> . end\n"
+    );
+}
+
+#[test]
+fn unbalanced_loops_are_rejected() {
+    assert!(bf::format(b"[[]", &bf::Layout::default()).is_err());
+}
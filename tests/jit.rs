@@ -0,0 +1,97 @@
+// Native code from bf::Jit, checked against known output and the VM
+#![cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+
+mod common;
+
+use common::{compile, FACTOR, HELLO, MANDELBROT};
+
+use bfinterp::bf::{self, Cell, Config, Error, TapePolicy};
+
+fn jit<C: Cell>(program: &bf::Program, input: &[u8], config: &Config) -> (bf::Result<()>, Vec<u8>) {
+    let mut input = input;
+    let mut output = Vec::new();
+    let result = bf::Jit::<C>::compile(program, config.clone()).and_then(|jit| jit.run(&mut input, &mut output));
+    (result, output)
+}
+
+fn vm<C: Cell>(program: &bf::Program, input: &[u8], config: &Config) -> (bf::Result<()>, Vec<u8>) {
+    let mut input = input;
+    let mut output = Vec::new();
+    let result = bf::Interpreter::<C, _, _>::with_config(program, &mut input, &mut output, config.clone()).run();
+    (result, output)
+}
+
+// Output of `source` on the JIT, which must match the VM's, error and all
+fn check<C: Cell>(source: &str, input: &[u8], config: &Config) -> bf::Result<Vec<u8>> {
+    let program = compile(source.as_bytes());
+    let (result, output) = jit::<C>(&program, input, config);
+    let (expected, expected_output) = vm::<C>(&program, input, config);
+    assert_eq!(output, expected_output, "{}", source);
+    match (result, expected) {
+        (Ok(()), Ok(())) => Ok(output),
+        (Err(e), Err(expected)) => {
+            assert_eq!(e.to_string(), expected.to_string(), "{}", source);
+            Err(e)
+        }
+        (result, expected) => panic!("{}: {:?}, expected {:?}", source, result, expected),
+    }
+}
+
+#[test]
+fn hello() {
+    let config = Config::new();
+    assert_eq!(check::<u8>(HELLO, b"", &config).unwrap(), b"Hello World!\n");
+    assert_eq!(check::<u16>(HELLO, b"", &config).unwrap(), b"Hello World!\n");
+    assert_eq!(check::<u32>(HELLO, b"", &config).unwrap(), b"Hello World!\n");
+    assert_eq!(check::<u64>(HELLO, b"", &config).unwrap(), b"Hello World!\n");
+}
+
+#[test]
+fn factor() {
+    let output = check::<u8>(FACTOR, b"123456\n", &Config::new()).unwrap();
+    assert_eq!(output, b"123456: 2 2 2 2 2 2 3 643\n");
+}
+
+#[test]
+fn mandelbrot() {
+    let program = compile(MANDELBROT.as_bytes());
+    let (result, output) = jit::<u8>(&program, b"", &Config::new());
+    result.unwrap();
+    assert!(output == include_bytes!("expected/mandelbrot.txt"));
+}
+
+#[test]
+fn limits_are_refused() {
+    let program = compile(HELLO.as_bytes());
+    assert!(matches!(
+        bf::Jit::<u8>::compile(&program, Config::new().max_steps(1000)),
+        Err(Error::Unsupported(_))
+    ));
+}
+
+// Going off each end, directly, through an offset and in the fused loops
+const EDGES: &[&str] = &[
+    "<+.",
+    ">>>>+.<<<<<-.",
+    "+>+>+[<]<.",
+    ">+>+>+[>]+.",
+    "+++[->>>>>+<<<<<]>>>>>.",
+    "+++[-<<+>>]<<.",
+    "+>+>+<<[>[->+<]<<]",
+    ">+++[-]>[-]>[-]>[-]<<<<[-]<[-]<[-].",
+    "++++[>+>++<<-]>>>.",
+];
+
+#[test]
+fn tape_policies() {
+    for policy in [TapePolicy::Wrap, TapePolicy::Error, TapePolicy::GrowRight, TapePolicy::Grow] {
+        let config = Config::new().tape_size(4).tape_policy(policy);
+        for source in EDGES {
+            let ok = check::<u8>(source, b"", &config).is_ok();
+            assert_eq!(check::<u32>(source, b"", &config).is_ok(), ok, "{} ({:?})", source, policy);
+            if let TapePolicy::Wrap | TapePolicy::Grow = policy {
+                assert!(ok, "{} ({:?})", source, policy);
+            }
+        }
+    }
+}
@@ -0,0 +1,54 @@
+// bf::Lossless keeps every byte of the source
+
+mod common;
+
+use common::PROGRAMS;
+
+use bfinterp::bf;
+use bfinterp::bf::Token;
+
+fn lexemes(source: &[u8]) -> Vec<bf::Lexeme> {
+    bf::Lexer::new(source)
+        .lossless()
+        .collect::<bf::Result<Vec<_>>>()
+        .unwrap()
+}
+
+#[test]
+fn lexemes_give_back_the_source() {
+    for &(name, source) in PROGRAMS {
+        let lexemes = lexemes(source.as_bytes());
+        let mut text = Vec::new();
+        for lexeme in &lexemes {
+            lexeme.write_to(&mut text).unwrap();
+        }
+        assert_eq!(text, source.as_bytes(), "{}", name);
+
+        let tokens = bf::Lexer::new(source.as_bytes())
+            .collect::<bf::Result<Vec<_>>>()
+            .unwrap();
+        let plain: Vec<_> = lexemes.iter().map(|l| (l.token, l.pos)).collect();
+        assert_eq!(plain, tokens, "{}", name);
+    }
+}
+
+#[test]
+fn trivia_ends_at_a_newline_or_command() {
+    let lexemes = lexemes(b"set up\n++ two\n  [ loop -]\nend");
+    let trivia: Vec<_> = lexemes
+        .iter()
+        .map(|l| (l.token, &l.leading[..], &l.trailing[..]))
+        .collect();
+    assert_eq!(
+        trivia,
+        vec![
+            (Token::Increment, &b"set up\n"[..], &b""[..]),
+            (Token::Increment, &b""[..], &b" two"[..]),
+            (Token::LeftLoop, &b"\n  "[..], &b" loop "[..]),
+            (Token::Decrement, &b""[..], &b""[..]),
+            (Token::RightLoop, &b""[..], &b""[..]),
+            (Token::Eof, &b"\nend"[..], &b""[..]),
+        ]
+    );
+    assert_eq!(lexemes[2].pos.line, 3);
+}
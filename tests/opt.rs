@@ -0,0 +1,75 @@
+// Code generation at each optimization level and with single passes left out
+
+mod common;
+
+use common::{compile_with, parse, FACTOR, MANDELBROT};
+
+use bfinterp::bf::{self, Instruction, OptConfig, Pass};
+
+use std::io;
+
+fn run(program: &bf::Program, input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    bf::Interpreter::<u8, _, _>::new(program, &mut io::Cursor::new(input), &mut output)
+        .run()
+        .unwrap();
+    output
+}
+
+#[test]
+fn raw_code_follows_the_commands() {
+    let program = compile_with(b"+++[->++<]>[-]>>[>]<.,", &OptConfig::none());
+    assert_eq!(
+        program.instructions,
+        [
+            Instruction::Add(0, 3),
+            Instruction::Jez(5),
+            Instruction::Add(0, -1),
+            Instruction::Right(1),
+            Instruction::Add(0, 2),
+            Instruction::Left(1),
+            Instruction::Jnz(5),
+            Instruction::Right(1),
+            Instruction::Jez(2),
+            Instruction::Add(0, -1),
+            Instruction::Jnz(2),
+            Instruction::Right(2),
+            Instruction::Jez(2),
+            Instruction::Right(1),
+            Instruction::Jnz(2),
+            Instruction::Left(1),
+            Instruction::Write(0),
+            Instruction::Read,
+            Instruction::Halt,
+        ]
+    );
+    assert_eq!(program.instructions.len(), program.spans.len());
+    assert_eq!(bf::generate_raw_code(parse(b"+>-")).unwrap(), compile_with(b"+>-", &OptConfig::level(0).unwrap()));
+}
+
+#[test]
+fn levels_differ_only_in_the_mandel_pass() {
+    let source = MANDELBROT.as_bytes();
+    let mandel = |p: &bf::Program| p.instructions.iter().any(|i| matches!(i, Instruction::Mandel(..)));
+    assert!(mandel(&compile_with(source, &OptConfig::level(2).unwrap())));
+    assert!(!mandel(&compile_with(source, &OptConfig::level(1).unwrap())));
+    assert_eq!(OptConfig::level(1).unwrap().enable(Pass::Mandel), OptConfig::default());
+    assert_eq!(OptConfig::level(3), None);
+}
+
+#[test]
+fn each_pass_can_be_left_out() {
+    let source = FACTOR.as_bytes();
+    let expected = run(&compile_with(source, &OptConfig::none()), b"123456\n");
+    for &pass in &Pass::ALL {
+        assert_eq!(pass.name().parse::<Pass>(), Ok(pass));
+        for opt in [OptConfig::default().disable(pass), OptConfig::none().enable(pass)] {
+            assert_eq!(run(&compile_with(source, &opt), b"123456\n"), expected, "{:?}", opt);
+        }
+    }
+    let zeros = |opt| compile_with(b"[-]>[-]>[-]", &opt).instructions;
+    assert_eq!(zeros(OptConfig::default()), [Instruction::ZeroRight(2), Instruction::Zero, Instruction::Halt]);
+    assert!(!zeros(OptConfig::default().disable(Pass::ZeroRuns))
+        .iter()
+        .any(|i| matches!(i, Instruction::ZeroRight(_))));
+}
@@ -0,0 +1,160 @@
+// Saving and resuming runs with bf::Snapshot
+
+mod common;
+
+use common::{compile, FACTOR};
+
+use bfinterp::bf::{self, Config, Error, Limit, Limits, Profile, Snapshot, Status, TapePolicy};
+
+// A run of `program` on `input` stopped after `steps` steps, its output and
+// the input it left unread
+fn stopped<'a>(program: &bf::Program, input: &'a [u8], steps: u64) -> (Snapshot<u8>, Vec<u8>, &'a [u8]) {
+    let mut input = input;
+    let mut output = Vec::new();
+    let config = Config::new().max_steps(steps);
+    let mut bf = bf::Interpreter::<u8, _, _>::with_config(program, &mut input, &mut output, config);
+    assert!(matches!(bf.run(), Err(Error::LimitExceeded(Limit::Steps, _))));
+    let snapshot = bf.snapshot();
+    (snapshot, output, input)
+}
+
+fn saved(snapshot: &Snapshot<u8>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    snapshot.write_to(&mut bytes).unwrap();
+    bytes
+}
+
+// Restores `snapshot` into a fresh interpreter and runs it to the end
+fn resumed(program: &bf::Program, snapshot: Snapshot<u8>, input: &[u8]) -> bf::Result<Vec<u8>> {
+    let mut input = input;
+    let mut output = Vec::new();
+    let mut bf = bf::Interpreter::<u8, _, _>::new(program, &mut input, &mut output);
+    bf.restore(snapshot)?;
+    bf.run()?;
+    Ok(output)
+}
+
+#[test]
+fn round_trip_carries_on_where_it_stopped() {
+    let program = compile(FACTOR.as_bytes());
+    let (snapshot, mut output, rest) = stopped(&program, b"123456\n", 20_000);
+    let read = Snapshot::<u8>::read_from(&mut &saved(&snapshot)[..]).unwrap();
+    assert_eq!(read, snapshot);
+    assert_eq!(read.usage().steps, 20_000);
+    assert_eq!(read.fingerprint(), program.fingerprint());
+
+    let mut read = read;
+    read.set_limits(Limits::default());
+    output.extend(resumed(&program, read, rest).unwrap());
+    assert_eq!(output, b"123456: 2 2 2 2 2 2 3 643\n");
+}
+
+#[test]
+fn truncated_and_corrupt_files_are_rejected() {
+    let program = compile(b"+[>+<+++++]>.");
+    let (snapshot, ..) = stopped(&program, b"", 10);
+    let bytes = saved(&snapshot);
+    for len in 0..bytes.len() {
+        assert!(Snapshot::<u8>::read_from(&mut &bytes[..len]).is_err(), "{} bytes", len);
+    }
+
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert!(Snapshot::<u8>::read_from(&mut &trailing[..]).is_err());
+    let mut magic = bytes.clone();
+    magic[0] = b'X';
+    assert!(Snapshot::<u8>::read_from(&mut &magic[..]).is_err());
+    let mut version = bytes.clone();
+    version[6] = 99;
+    assert!(Snapshot::<u8>::read_from(&mut &version[..]).is_err());
+    assert!(Snapshot::<u16>::read_from(&mut &bytes[..]).is_err());
+}
+
+#[test]
+fn snapshots_of_other_programs_are_refused() {
+    let program = compile(b"+[>+<+++++]>.");
+    let (snapshot, ..) = stopped(&program, b"", 10);
+    let other = compile(b"+[>++<+++++]>.");
+    assert!(matches!(resumed(&other, snapshot, b""), Err(Error::Format(_))));
+}
+
+#[test]
+fn resuming_past_a_limit() {
+    // add 0 3, then [-]>[-]> as a zero_right 2 charged two steps at once
+    let program = compile(b"+++[-]>[-]>");
+    let (snapshot, ..) = stopped(&program, b"", 1);
+
+    // Already past the new limit: refused outright
+    let mut over = snapshot.clone();
+    over.set_limits(Limits {
+        steps: Some(0),
+        ..Limits::default()
+    });
+    assert!(matches!(resumed(&program, over, b""), Err(Error::LimitExceeded(Limit::Steps, None))));
+
+    // At the limit: stops before the next step, multi-step instructions included
+    let mut input: &[u8] = b"";
+    let mut output = Vec::new();
+    let mut bf = bf::Interpreter::<u8, _, _>::new(&program, &mut input, &mut output);
+    bf.restore(snapshot).unwrap();
+    assert!(matches!(bf.run(), Err(Error::LimitExceeded(Limit::Steps, _))));
+    assert_eq!(bf.usage().steps, 1);
+
+    // Output and cells are checked the same way
+    let program = compile(b"+.>.>.>.");
+    let mut input: &[u8] = b"";
+    let mut output = Vec::new();
+    let config = Config::new().max_output(3);
+    let mut bf = bf::Interpreter::<u8, _, _>::with_config(&program, &mut input, &mut output, config);
+    assert!(bf.run().is_err());
+    let mut snapshot = bf.snapshot();
+    snapshot.set_limits(Limits {
+        output: Some(2),
+        ..Limits::default()
+    });
+    assert!(matches!(resumed(&program, snapshot.clone(), b""), Err(Error::LimitExceeded(Limit::Output, None))));
+    snapshot.set_limits(Limits {
+        cells: Some(1),
+        ..Limits::default()
+    });
+    assert!(matches!(resumed(&program, snapshot, b""), Err(Error::LimitExceeded(Limit::Cells, None))));
+}
+
+#[test]
+fn failed_instructions_count_once() {
+    // The second `.` passes the output limit, and runs again once resumed
+    let program = compile(b"+..");
+    let mut input: &[u8] = b"";
+    let mut output = Vec::new();
+    let config = Config::new().max_output(1);
+    let profile = Profile::new(&program);
+    let mut bf = bf::Interpreter::<u8, _, _, _>::with_observer(&program, &mut input, &mut output, config, profile);
+    assert!(matches!(bf.step(10), Status::Error(Error::LimitExceeded(Limit::Output, _))));
+    assert_eq!(bf.usage().steps, 2);
+    assert_eq!(bf.usage().output, 1);
+    assert_eq!(bf.observer().hits(bf.pc()), 0);
+    let mut snapshot = bf.snapshot();
+    snapshot.set_limits(Limits {
+        output: Some(2),
+        ..Limits::default()
+    });
+
+    let mut input: &[u8] = b"";
+    let mut output = Vec::new();
+    let mut bf = bf::Interpreter::<u8, _, _>::new(&program, &mut input, &mut output);
+    bf.restore(snapshot).unwrap();
+    bf.run().unwrap();
+    assert_eq!(bf.usage().steps, 4);
+    assert_eq!(bf.usage().output, 2);
+
+    // A search keeps the passes it finished before running off the tape
+    let program = compile(b"+>+>+<<[>]");
+    let mut input: &[u8] = b"";
+    let mut output = Vec::new();
+    let config = Config::new().tape_size(3).tape_policy(TapePolicy::Error).max_steps(100);
+    let profile = Profile::new(&program);
+    let mut bf = bf::Interpreter::<u8, _, _, _>::with_observer(&program, &mut input, &mut output, config, profile);
+    assert!(matches!(bf.step(10), Status::Error(Error::TapeOutOfBounds(_))));
+    assert_eq!(bf.usage().steps, 3 + 2);
+    assert_eq!(bf.observer().hits(bf.pc()), 0);
+}
@@ -0,0 +1,82 @@
+// Leaving the tape, which the optimized code must do at the same command as
+// the commands themselves however its moves are folded
+
+mod common;
+
+use common::{compile_with, EXCURSIONS};
+
+use bfinterp::bf::{self, CellWidth, Config, Error, OptConfig, TapePolicy};
+
+// Output of `source` and where it failed, if it did
+fn token_level(source: &str, config: &Config) -> (Vec<u8>, Option<String>) {
+    let mut input: &[u8] = b"";
+    let mut output = Vec::new();
+    let tokens = bf::Lexer::new(source.as_bytes());
+    let result = bf::OldInterpreter::<u8, _, _, _>::with_config(tokens, &mut input, &mut output, config.clone()).run();
+    (output, failure(result))
+}
+
+fn vm(program: &bf::Program, config: &Config) -> (Vec<u8>, Option<String>) {
+    let mut input: &[u8] = b"";
+    let mut output = Vec::new();
+    let result = bf::Interpreter::<u8, _, _>::with_config(program, &mut input, &mut output, config.clone()).run();
+    (output, failure(result))
+}
+
+fn failure(result: bf::Result<()>) -> Option<String> {
+    match result {
+        Ok(()) => None,
+        Err(e @ Error::TapeOutOfBounds(_)) => Some(e.position().unwrap().to_string()),
+        Err(e) => panic!("{}", e),
+    }
+}
+
+#[test]
+fn excursions_leave_where_the_commands_do() {
+    let config = Config::new().tape_size(5).tape_policy(TapePolicy::Error).max_steps(10_000);
+    for &(source, at) in EXCURSIONS {
+        let expected = token_level(source, &config);
+        assert_eq!(expected.1.as_deref(), Some(at), "{}", source);
+        for opt in [OptConfig::none(), OptConfig::level(1).unwrap(), OptConfig::all()] {
+            let program = compile_with(source.as_bytes(), &opt);
+            assert_eq!(vm(&program, &config), expected, "{} ({:?})", source, opt);
+        }
+    }
+}
+
+#[test]
+fn excursions_still_leave_after_emit() {
+    let config = Config::new().tape_size(5).tape_policy(TapePolicy::Error).max_steps(10_000);
+    for &(source, _) in EXCURSIONS {
+        let mut text = Vec::new();
+        bf::backend::brainfuck::emit(&compile_with(source.as_bytes(), &OptConfig::all()), CellWidth::U8, &mut text).unwrap();
+        let emitted = String::from_utf8(text).unwrap();
+        assert!(token_level(&emitted, &config).1.is_some(), "{} emitted as {}", source, emitted);
+    }
+}
+
+#[test]
+fn excursions_grow_the_tape() {
+    for policy in [TapePolicy::GrowRight, TapePolicy::Grow] {
+        let config = Config::new().tape_size(1).tape_policy(policy).max_steps(10_000);
+        for source in [">>><<<+.", "+>>><<<.", "<>+.", "+<>."] {
+            let expected = token_level(source, &config);
+            for opt in [OptConfig::none(), OptConfig::all()] {
+                let program = compile_with(source.as_bytes(), &opt);
+                assert_eq!(vm(&program, &config), expected, "{} ({:?}, {:?})", source, opt, policy);
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+#[test]
+fn jit_excursions_leave_where_the_commands_do() {
+    let config = Config::new().tape_size(5).tape_policy(TapePolicy::Error);
+    for &(source, at) in EXCURSIONS {
+        let jit = bf::Jit::<u8>::compile(&compile_with(source.as_bytes(), &OptConfig::all()), config.clone()).unwrap();
+        let mut input: &[u8] = b"";
+        let mut output = Vec::new();
+        assert_eq!(failure(jit.run(&mut input, &mut output)).as_deref(), Some(at), "{}", source);
+    }
+}
@@ -0,0 +1,151 @@
+// Runs modules from bf::backend::wasm in wasmi and checks them against the interpreter
+
+mod common;
+
+use common::{compile, FACTOR, HELLO};
+
+use bfinterp::bf;
+use bfinterp::bf::{CellWidth, Config, EofPolicy, TapePolicy};
+
+use wasmi::{Caller, Engine, Linker, Module, Store, Val};
+
+struct Io {
+    input: Vec<u8>,
+    output: Vec<u8>,
+}
+
+fn interpret(program: &bf::Program, width: CellWidth, config: &Config, input: &[u8]) -> bf::Result<Vec<u8>> {
+    let mut input = input;
+    let mut output = Vec::new();
+    let config = config.clone();
+    match width {
+        CellWidth::U8 => bf::Interpreter::<u8, _, _>::with_config(program, &mut input, &mut output, config).run(),
+        CellWidth::U16 => bf::Interpreter::<u16, _, _>::with_config(program, &mut input, &mut output, config).run(),
+        CellWidth::U32 => bf::Interpreter::<u32, _, _>::with_config(program, &mut input, &mut output, config).run(),
+        CellWidth::U64 => bf::Interpreter::<u64, _, _>::with_config(program, &mut input, &mut output, config).run(),
+    }?;
+    Ok(output)
+}
+
+// Output, or the index of the faulting instruction
+fn run_wasm(wasm: &[u8], input: &[u8]) -> Result<Vec<u8>, usize> {
+    let engine = Engine::default();
+    let module = Module::new(&engine, wasm).unwrap();
+    let io = Io {
+        input: input.iter().rev().cloned().collect(),
+        output: Vec::new(),
+    };
+    let mut store = Store::new(&engine, io);
+    let mut linker = Linker::new(&engine);
+    linker
+        .func_wrap("env", "read", |mut caller: Caller<'_, Io>| match caller.data_mut().input.pop() {
+            Some(b) => b as i32,
+            None => -1,
+        })
+        .unwrap();
+    linker
+        .func_wrap("env", "write", |mut caller: Caller<'_, Io>, b: i32| {
+            caller.data_mut().output.push(b as u8)
+        })
+        .unwrap();
+    let instance = linker.instantiate(&mut store, &module).unwrap().start(&mut store).unwrap();
+    let run = instance.get_typed_func::<(), ()>(&store, "run").unwrap();
+    let result = run.call(&mut store, ());
+    match instance.get_global(&store, "fault").unwrap().get(&store) {
+        Val::I32(-1) => {
+            result.unwrap();
+            Ok(store.into_data().output)
+        }
+        Val::I32(idx) => Err(idx as usize),
+        v => panic!("fault has the wrong type: {:?}", v),
+    }
+}
+
+fn wasm(program: &bf::Program, width: CellWidth, config: &Config) -> Vec<u8> {
+    let mut out = Vec::new();
+    bf::backend::wasm::emit_wasm(program, width, config, &mut out).unwrap();
+    out
+}
+
+fn wat(program: &bf::Program, width: CellWidth, config: &Config) -> Vec<u8> {
+    let mut out = Vec::new();
+    bf::backend::wasm::emit_wat(program, width, config, &mut out).unwrap();
+    wat::parse_str(String::from_utf8(out).unwrap()).unwrap()
+}
+
+// Runs both encodings and checks they print what the interpreter prints
+fn check(source: &str, width: CellWidth, config: &Config, input: &[u8]) -> Vec<u8> {
+    let program = compile(source.as_bytes());
+    let expected = interpret(&program, width, config, input).unwrap();
+    assert_eq!(run_wasm(&wasm(&program, width, config), input), Ok(expected.clone()));
+    assert_eq!(run_wasm(&wat(&program, width, config), input), Ok(expected.clone()));
+    expected
+}
+
+#[test]
+fn hello() {
+    let out = check(HELLO, CellWidth::U8, &Config::new(), b"");
+    assert_eq!(out, b"Hello World!\n");
+}
+
+#[test]
+fn factor_all_widths() {
+    for &width in &[CellWidth::U8, CellWidth::U16, CellWidth::U32, CellWidth::U64] {
+        let out = check(FACTOR, width, &Config::new(), b"3604\n");
+        assert_eq!(out, b"3604: 2 2 17 53\n");
+    }
+}
+
+#[test]
+fn multiplies_at_all_widths() {
+    // The first loop runs once per value of the cell, so its products wrap
+    let source = "-[->+++>>-----<<<]>.>>.++++[->+++>++<<]>.>.";
+    for &width in &[CellWidth::U8, CellWidth::U16, CellWidth::U32, CellWidth::U64] {
+        check(source, width, &Config::new(), b"");
+    }
+    let left = "+++++[<<+++>>-]<<.";
+    check(left, CellWidth::U16, &Config::new().tape_size(1).tape_policy(TapePolicy::Grow), b"");
+}
+
+#[test]
+fn eof_policies() {
+    let source = "+++,.[-]>,.";
+    for &eof in &[EofPolicy::Unchanged, EofPolicy::Zero, EofPolicy::Max] {
+        for &width in &[CellWidth::U8, CellWidth::U64] {
+            check(source, width, &Config::new().eof_policy(eof), b"A");
+        }
+    }
+}
+
+#[test]
+fn wraps_at_both_ends() {
+    let config = Config::new().tape_size(7);
+    check("+<<<<<<<<<<<<<<<<<<<<<<+++.>>>>>>>>>>>>.", CellWidth::U8, &config, b"");
+    check("-[>-]>>>>>>>+.", CellWidth::U16, &config, b"");
+}
+
+#[test]
+fn grows_tape() {
+    let source = "++++[<++++++++++>-]<[<+>-]<+++++.>>>>>>>>>>>>>>+++.[<]<<<<.";
+    check(source, CellWidth::U8, &Config::new().tape_size(2).tape_policy(TapePolicy::Grow), b"");
+    check(source, CellWidth::U32, &Config::new().tape_size(3).tape_policy(TapePolicy::Grow), b"");
+    let right = ">>>>>+++++[<++++++++++>-]<.[>+++>]<.";
+    check(right, CellWidth::U8, &Config::new().tape_size(2).tape_policy(TapePolicy::GrowRight), b"");
+}
+
+#[test]
+fn out_of_bounds_reports_instruction() {
+    let program = compile(b"+\n+[>+]");
+    let config = Config::new().tape_size(5).tape_policy(TapePolicy::Error);
+    let idx = run_wasm(&wasm(&program, CellWidth::U8, &config), b"").unwrap_err();
+    assert_eq!(program.span(idx).unwrap().start.to_string(), "2:3");
+    match interpret(&program, CellWidth::U8, &config, b"") {
+        Err(bf::Error::TapeOutOfBounds(Some(pos))) => assert_eq!(pos.to_string(), "2:3"),
+        r => panic!("expected an out of bounds error, got {:?}", r),
+    }
+
+    let program = compile(b">>+<<<");
+    let config = Config::new().tape_size(1).tape_policy(TapePolicy::GrowRight);
+    let idx = run_wasm(&wasm(&program, CellWidth::U16, &config), b"").unwrap_err();
+    assert_eq!(program.span(idx).unwrap().start.to_string(), "1:4");
+}